@@ -0,0 +1,330 @@
+/*!
+OCI runtime command line interface on top of [`nswrap::Wrap`].
+
+```text
+nswrap-runtime [--root <dir>] create [--bundle <dir>] [--pid-file <file>] <id>
+nswrap-runtime [--root <dir>] start <id>
+nswrap-runtime [--root <dir>] state <id>
+nswrap-runtime [--root <dir>] kill <id> [<signal>]
+nswrap-runtime [--root <dir>] delete [--force] <id>
+nswrap-runtime features
+```
+
+State is kept in `$XDG_RUNTIME_DIR/nswrap/<id>`, or `/run/nswrap/<id>`
+without a runtime dir. `create` sets up the container and leaves its
+process blocked on `exec.fifo` in that directory, until `start` writes
+to it. `features` prints the `features.json` of [`nswrap::oci::features()`].
+
+Hooks of the bundle run as described by [`Spec::apply_hooks()`],
+`poststart` ones by `start` and `poststop` ones by `delete`.
+*/
+use nswrap::oci::{run_hooks, Spec, State, Status};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+
+struct Args {
+    root: Option<PathBuf>,
+    command: String,
+    flags: HashMap<String, String>,
+    positional: Vec<String>,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = std::env::args().skip(1);
+        let mut root = None;
+        let mut command = None;
+        let mut flags = HashMap::new();
+        let mut positional = Vec::new();
+        while let Some(arg) = args.next() {
+            let (name, value) = match arg.strip_prefix("--").or(arg.strip_prefix('-')) {
+                Some(flag) if !flag.is_empty() => match flag.split_once('=') {
+                    Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                    None => (flag.to_string(), None),
+                },
+                _ => {
+                    match command {
+                        None => command = Some(arg),
+                        Some(_) => positional.push(arg),
+                    }
+                    continue;
+                }
+            };
+            let value = match (name.as_str(), value) {
+                ("force" | "f" | "all" | "a", None) => "true".to_string(),
+                (_, Some(value)) => value,
+                (_, None) => args.next().ok_or(format!("--{} needs a value", name))?,
+            };
+            match (name.as_str(), &command) {
+                ("root", None) => root = Some(value.into()),
+                // Global options of other runtimes that do not apply.
+                ("log" | "log-format" | "systemd-cgroup", None) => (),
+                ("b", _) => {
+                    flags.insert("bundle".into(), value);
+                }
+                _ => {
+                    flags.insert(name, value);
+                }
+            }
+        }
+        Ok(Self {
+            root,
+            command: command.ok_or("no command given")?,
+            flags,
+            positional,
+        })
+    }
+
+    fn id(&self) -> Result<&str, String> {
+        self.positional
+            .first()
+            .map(|s| s.as_str())
+            .ok_or_else(|| format!("{}: no container id given", self.command))
+    }
+
+    fn state_dir(&self, id: &str) -> PathBuf {
+        let root = match &self.root {
+            Some(root) => root.clone(),
+            None => xdg::BaseDirectories::with_prefix("nswrap")
+                .ok()
+                .and_then(|dirs| dirs.get_runtime_directory().ok().map(|d| d.join("nswrap")))
+                .unwrap_or_else(|| "/run/nswrap".into()),
+        };
+        root.join(id)
+    }
+}
+
+fn main() {
+    let res = Args::parse().and_then(|args| {
+        let res = match args.command.as_str() {
+            "create" => create(&args),
+            "start" => start(&args),
+            "state" => state(&args),
+            "kill" => kill(&args),
+            "delete" => delete(&args),
+            "features" => features(),
+            cmd => return Err(format!("unknown command {}", cmd)),
+        };
+        res.map_err(|e| format!("{}: {}", args.command, e))
+    });
+    if let Err(e) = res {
+        eprintln!("nswrap-runtime: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn create(args: &Args) -> Result<(), String> {
+    if args.flags.contains_key("console-socket") {
+        return Err("--console-socket is not supported".into());
+    }
+    let id = args.id()?;
+    let bundle = std::path::absolute(args.flags.get("bundle").map(|s| s.as_str()).unwrap_or("."))
+        .map_err(|e| e.to_string())?;
+    let spec = Spec::load(&bundle).map_err(|e| e.to_string())?;
+    let mut wrap = nswrap::Wrap::new();
+    spec.apply(&mut wrap, &bundle).map_err(|e| e.to_string())?;
+
+    let dir = args.state_dir(id);
+    if dir.exists() {
+        return Err(format!("container {} exists", id));
+    }
+    std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    let state = State {
+        oci_version: spec.oci_version.clone(),
+        id: id.into(),
+        status: Status::Creating,
+        pid: None,
+        bundle,
+        annotations: spec.annotations.clone(),
+    };
+    let res = create_in(args, &dir, &spec, wrap, state);
+    if res.is_err() {
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    res
+}
+
+/// Start the container of `create` with its state in `dir`.
+fn create_in(
+    args: &Args,
+    dir: &Path,
+    spec: &Spec,
+    mut wrap: nswrap::Wrap,
+    mut state: State,
+) -> Result<(), String> {
+    use rustix::fs::{mknodat, open, FileType, Mode, OFlags, CWD};
+
+    save(dir, &state)?;
+
+    // Opened read-write, so that neither side blocks on `open(2)`,
+    // the container then blocks on reading it until `start` writes.
+    let fifo = dir.join("exec.fifo");
+    mknodat(CWD, &fifo, FileType::Fifo, Mode::from_raw_mode(0o600), 0)
+        .map_err(|e| e.to_string())?;
+    let fd: OwnedFd =
+        open(&fifo, OFlags::RDWR | OFlags::CLOEXEC, Mode::empty()).map_err(|e| e.to_string())?;
+    wrap.callback(move || {
+        let mut buf = [0u8; 1];
+        if std::fs::File::from(fd).read_exact(&mut buf).is_err() {
+            unsafe { libc::_exit(127) };
+        }
+        0
+    });
+    spec.apply_hooks(&mut wrap, &state)
+        .map_err(|e| e.to_string())?;
+    let child = wrap.spawn().map_err(|e| e.to_string())?;
+
+    state.pid = Some(child.id() as i32);
+    state.status = Status::Created;
+    let res = save(dir, &state).and_then(|_| match args.flags.get("pid-file") {
+        Some(pid_file) => {
+            std::fs::write(pid_file, format!("{}", child.id())).map_err(|e| e.to_string())
+        }
+        None => Ok(()),
+    });
+    if res.is_err() {
+        // Nothing could delete a container without its state.
+        unsafe { libc::kill(child.id() as i32, libc::SIGKILL) };
+    }
+    res
+}
+
+fn start(args: &Args) -> Result<(), String> {
+    let dir = args.state_dir(args.id()?);
+    let mut state = load(&dir)?;
+    if state.status != Status::Created {
+        return Err(format!("container is {}", status_name(state.status)));
+    }
+    let fifo = dir.join("exec.fifo");
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&fifo)
+        .map_err(|e| e.to_string())?;
+    f.write_all(b"0").map_err(|e| e.to_string())?;
+    let _ = std::fs::remove_file(&fifo);
+    state.status = Status::Running;
+    save(&dir, &state)?;
+    // Failures of these are only warnings.
+    if let Some(hooks) = Spec::load(&state.bundle).ok().and_then(|spec| spec.hooks) {
+        if let Err(e) = run_hooks(&hooks.poststart, &state) {
+            eprintln!("nswrap-runtime: poststart: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn state(args: &Args) -> Result<(), String> {
+    let state = load(&args.state_dir(args.id()?))?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&state).map_err(|e| e.to_string())?
+    );
+    Ok(())
+}
+
+fn features() -> Result<(), String> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&nswrap::oci::features()).map_err(|e| e.to_string())?
+    );
+    Ok(())
+}
+
+fn kill(args: &Args) -> Result<(), String> {
+    let state = load(&args.state_dir(args.id()?))?;
+    let signal = parse_signal(args.positional.get(1).map(|s| s.as_str()).unwrap_or("TERM"))?;
+    let pid = match (state.status, state.pid) {
+        (Status::Created | Status::Running, Some(pid)) => pid,
+        _ => return Err(format!("container is {}", status_name(state.status))),
+    };
+    if unsafe { libc::kill(pid, signal) } != 0 {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+    Ok(())
+}
+
+fn delete(args: &Args) -> Result<(), String> {
+    let dir = args.state_dir(args.id()?);
+    let mut state = load(&dir)?;
+    match (state.status, state.pid) {
+        (Status::Stopped | Status::Creating, _) | (_, None) => (),
+        (_, Some(pid)) if args.flags.contains_key("force") => unsafe {
+            libc::kill(pid, libc::SIGKILL);
+        },
+        (status, _) => return Err(format!("container is {}", status_name(status))),
+    }
+    std::fs::remove_dir_all(&dir).map_err(|e| e.to_string())?;
+    state.status = Status::Stopped;
+    if let Some(hooks) = Spec::load(&state.bundle).ok().and_then(|spec| spec.hooks) {
+        if let Err(e) = run_hooks(&hooks.poststop, &state) {
+            eprintln!("nswrap-runtime: poststop: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn save(dir: &Path, state: &State) -> Result<(), String> {
+    let json = serde_json::to_vec(state).map_err(|e| e.to_string())?;
+    // Replaced atomically, other commands may read it concurrently.
+    let tmp = dir.join("state.json.tmp");
+    std::fs::write(&tmp, json).map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp, dir.join("state.json")).map_err(|e| e.to_string())
+}
+
+/// Read the state of a container, updating its status if it exited.
+fn load(dir: &Path) -> Result<State, String> {
+    let json = std::fs::read(dir.join("state.json")).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => "container does not exist".to_string(),
+        _ => e.to_string(),
+    })?;
+    let mut state: State = serde_json::from_slice(&json).map_err(|e| e.to_string())?;
+    if let Some(pid) = state.pid {
+        if !is_alive(pid) {
+            state.status = Status::Stopped;
+        }
+    }
+    Ok(state)
+}
+
+/// Whether `pid` is running, zombies that are not reaped yet are not.
+fn is_alive(pid: i32) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        // The state follows the command name, which is in parentheses.
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .map(|(_, rest)| !rest.trim_start().starts_with(['Z', 'X']))
+            .unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+fn status_name(status: Status) -> &'static str {
+    match status {
+        Status::Creating => "creating",
+        Status::Created => "created",
+        Status::Running => "running",
+        Status::Stopped => "stopped",
+    }
+}
+
+fn parse_signal(s: &str) -> Result<i32, String> {
+    if let Ok(n) = s.parse() {
+        return Ok(n);
+    }
+    let name = s.strip_prefix("SIG").unwrap_or(s);
+    Ok(match name {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        "CONT" => libc::SIGCONT,
+        "STOP" => libc::SIGSTOP,
+        "WINCH" => libc::SIGWINCH,
+        _ => return Err(format!("unknown signal {}", s)),
+    })
+}
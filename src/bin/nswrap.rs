@@ -0,0 +1,388 @@
+/*!
+Command line interface compatible with the options of bubblewrap.
+
+```text
+nswrap [OPTION...] [--] COMMAND [ARG...]
+```
+
+Like `bwrap`, the command runs in a new mount namespace on a tmpfs root,
+which is built by the options in the order they are given. Options
+that nswrap cannot do are rejected, instead of being ignored.
+*/
+use nswrap::{config, Wrap};
+use std::ffi::{OsStr, OsString};
+use std::io::Read;
+use std::os::fd::{FromRawFd, IntoRawFd, OwnedFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "usage: nswrap [OPTIONS...] [--] COMMAND [ARGS...]
+
+    --help                       Print this help
+    --version                    Print version
+    --args FD                    Parse NUL-separated args from FD
+    --unshare-all                Unshare every namespace we support by default
+    --share-net                  Retain the network namespace (can only combine with --unshare-all)
+    --unshare-user               Create new user namespace
+    --unshare-user-try           Create new user namespace
+    --unshare-ipc                Create new ipc namespace
+    --unshare-pid                Create new pid namespace
+    --unshare-net                Create new network namespace
+    --unshare-uts                Create new uts namespace
+    --unshare-cgroup             Create new cgroup namespace
+    --unshare-cgroup-try         Create new cgroup namespace
+    --userns FD                  Use this user namespace
+    --pidns FD                   Use this pid namespace
+    --uid UID                    Custom uid in the sandbox (requires --unshare-user or --userns)
+    --gid GID                    Custom gid in the sandbox (requires --unshare-user or --userns)
+    --hostname NAME              Custom hostname in the sandbox (requires --unshare-uts)
+    --chdir DIR                  Change directory to DIR
+    --clearenv                   Unset all environment variables
+    --setenv VAR VALUE           Set an environment variable
+    --unsetenv VAR               Unset an environment variable
+    --bind SRC DEST              Bind mount the host path SRC on DEST
+    --bind-try SRC DEST          Equal to --bind but ignores non-existent SRC
+    --dev-bind SRC DEST          Bind mount the host path SRC on DEST, allowing device access
+    --dev-bind-try SRC DEST      Equal to --dev-bind but ignores non-existent SRC
+    --ro-bind SRC DEST           Bind mount the host path SRC readonly on DEST
+    --ro-bind-try SRC DEST       Equal to --ro-bind but ignores non-existent SRC
+    --remount-ro DEST            Remount DEST as readonly; does not recursively remount
+    --proc DEST                  Mount new procfs on DEST
+    --dev DEST                   Mount new dev on DEST, only /dev is supported
+    --tmpfs DEST                 Mount new tmpfs on DEST
+    --dir DEST                   Create dir at DEST
+    --file FD DEST               Copy from FD to destination DEST
+    --bind-data FD DEST          Copy from FD to file which is bind-mounted on DEST
+    --ro-bind-data FD DEST       Copy from FD to file which is readonly bind-mounted on DEST
+    --symlink SRC DEST           Create symlink at DEST with target SRC
+    --chmod OCTAL PATH           Change permissions of PATH (must already exist)
+    --perms OCTAL                Set permissions of next argument (--dir, --file, --tmpfs etc.)
+    --size BYTES                 Set size of next argument (only for --tmpfs)
+    --die-with-parent            Kills with SIGKILL child process when the parent process dies
+    --new-session                Create a new terminal session
+";
+
+/// Options of bwrap that have no equivalent here.
+const UNSUPPORTED: &[&str] = &[
+    "--level-prefix",
+    "--userns2",
+    "--disable-userns",
+    "--assert-userns-disabled",
+    "--mqueue",
+    "--overlay-src",
+    "--overlay",
+    "--tmp-overlay",
+    "--ro-overlay",
+    "--exec-label",
+    "--file-label",
+    "--seccomp",
+    "--add-seccomp-fd",
+    "--block-fd",
+    "--userns-block-fd",
+    "--info-fd",
+    "--json-status-fd",
+    "--sync-fd",
+    "--lock-file",
+    "--cap-add",
+    "--cap-drop",
+    "--as-pid-1",
+];
+
+#[derive(Default)]
+struct Options {
+    unshare_user: bool,
+    userns: bool,
+    unshare_uts: bool,
+    unshare_net: bool,
+    share_net: bool,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    hostname: Option<String>,
+    perms: Option<u32>,
+    size: Option<u64>,
+    chmods: Vec<(PathBuf, u32)>,
+    new_session: bool,
+}
+
+fn main() {
+    let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    match run(args) {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("nswrap: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run(args: Vec<OsString>) -> Result<i32, String> {
+    let mut wrap = Wrap::new();
+    wrap.unshare(config::NamespaceType::Mount).sandbox_mnt(true);
+    let mut opts = Options::default();
+    let command = parse(args, &mut wrap, &mut opts)?;
+    let (program, args) = command.split_first().ok_or("No command specified")?;
+
+    if (opts.uid.is_some() || opts.gid.is_some()) && !(opts.unshare_user || opts.userns) {
+        return Err("Specifying --uid or --gid requires --unshare-user or --userns".into());
+    }
+    if opts.hostname.is_some() && !opts.unshare_uts {
+        return Err("Specifying --hostname requires --unshare-uts".into());
+    }
+    if opts.unshare_net && !opts.share_net {
+        wrap.unshare(config::NamespaceType::Network);
+    }
+    if opts.unshare_user {
+        let uid = nswrap::util::get_uid();
+        let gid = nswrap::util::get_gid();
+        wrap.uid_map(uid, opts.uid.unwrap_or(uid), 1)
+            .gid_map(gid, opts.gid.unwrap_or(gid), 1);
+    }
+    if let Some(name) = opts.hostname {
+        wrap.hostname(name);
+    }
+    let chmods = std::mem::take(&mut opts.chmods);
+    let new_session = opts.new_session;
+    wrap.callback(move || {
+        if new_session {
+            unsafe { libc::setsid() };
+        }
+        for (path, mode) in &chmods {
+            let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+            if unsafe { libc::chmod(c_path.as_ptr(), *mode) } != 0 {
+                eprintln!(
+                    "nswrap: Can't chmod {:o} {}: {}",
+                    mode,
+                    path.display(),
+                    std::io::Error::last_os_error()
+                );
+                unsafe { libc::_exit(1) };
+            }
+        }
+        0
+    });
+    wrap.program(program).args(args);
+
+    let status = wrap.status().map_err(|e| e.to_string())?;
+    Ok(match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(sig)) => 128 + sig,
+        _ => 1,
+    })
+}
+
+/// Turn the options into builder calls, returning the command.
+fn parse(
+    args: Vec<OsString>,
+    wrap: &mut Wrap,
+    opts: &mut Options,
+) -> Result<Vec<OsString>, String> {
+    use config::NamespaceType::*;
+
+    let mut args = std::collections::VecDeque::from(args);
+    while let Some(arg) = args.pop_front() {
+        let Some(opt) = arg.to_str().filter(|a| a.starts_with("--")) else {
+            args.push_front(arg);
+            break;
+        };
+        let mut next = |n: usize| -> Result<Vec<OsString>, String> {
+            if args.len() < n {
+                return Err(format!("{} takes {} arguments", opt, n));
+            }
+            Ok(args.drain(..n).collect())
+        };
+        match opt {
+            "--" => break,
+            "--help" => {
+                print!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "--version" => {
+                println!("nswrap {}", env!("CARGO_PKG_VERSION"));
+                std::process::exit(0);
+            }
+            "--args" => {
+                let fd = parse_fd(&next(1)?[0])?;
+                let mut data = Vec::new();
+                std::fs::File::from(fd)
+                    .read_to_end(&mut data)
+                    .map_err(|e| format!("--args: {}", e))?;
+                // Inserted where --args was, like bwrap does.
+                let extra = data
+                    .split(|b| *b == 0)
+                    .filter(|a| !a.is_empty())
+                    .map(|a| OsString::from_vec(a.to_vec()))
+                    .collect::<Vec<_>>();
+                for a in extra.into_iter().rev() {
+                    args.push_front(a);
+                }
+            }
+            "--unshare-all" => {
+                opts.unshare_user = true;
+                opts.unshare_uts = true;
+                opts.unshare_net = true;
+                // The network namespace is unshared last, unless --share-net.
+                for typ in [User, Ipc, Pid, Uts, Cgroup] {
+                    wrap.unshare(typ);
+                }
+            }
+            "--share-net" => opts.share_net = true,
+            "--unshare-user" | "--unshare-user-try" => {
+                opts.unshare_user = true;
+                wrap.unshare(User);
+            }
+            "--unshare-ipc" => {
+                wrap.unshare(Ipc);
+            }
+            "--unshare-pid" => {
+                wrap.unshare(Pid);
+            }
+            "--unshare-net" => opts.unshare_net = true,
+            "--unshare-uts" => {
+                opts.unshare_uts = true;
+                wrap.unshare(Uts);
+            }
+            "--unshare-cgroup" | "--unshare-cgroup-try" => {
+                wrap.unshare(Cgroup);
+            }
+            "--userns" => {
+                opts.userns = true;
+                wrap.nsenter(User, parse_fd(&next(1)?[0])?.into_raw_fd());
+            }
+            "--pidns" => {
+                wrap.nsenter(Pid, parse_fd(&next(1)?[0])?.into_raw_fd());
+            }
+            "--uid" => opts.uid = Some(parse_num(&next(1)?[0], 10)?),
+            "--gid" => opts.gid = Some(parse_num(&next(1)?[0], 10)?),
+            "--hostname" => opts.hostname = Some(to_string(&next(1)?[0])?),
+            "--chdir" => {
+                wrap.current_dir(&next(1)?[0]);
+            }
+            "--clearenv" => {
+                wrap.env_clear();
+            }
+            "--setenv" => {
+                let a = next(2)?;
+                wrap.env(&a[0], &a[1]);
+            }
+            "--unsetenv" => {
+                wrap.env_remove(&next(1)?[0]);
+            }
+            "--bind" | "--bind-try" | "--dev-bind" | "--dev-bind-try" | "--ro-bind"
+            | "--ro-bind-try" => {
+                let a = next(2)?;
+                let (src, dest) = (Path::new(&a[0]), Path::new(&a[1]));
+                if opt.ends_with("-try") && !src.exists() {
+                    continue;
+                }
+                // Only the --dev-bind variants allow device access.
+                let mut options = vec!["rbind".to_string()];
+                if !opt.starts_with("--dev-") {
+                    options.push("nodev".into());
+                }
+                if opt.starts_with("--ro-") {
+                    options.push("ro".into());
+                }
+                let mnt = config::MountBuilder::default()
+                    .destination(dest.to_path_buf())
+                    .typ(None)
+                    .source(Some(src.to_path_buf()))
+                    .options(Some(options))
+                    .build()
+                    .map_err(|e| e.to_string())?;
+                wrap.mount(mnt);
+            }
+            "--remount-ro" => {
+                wrap.remount_ro(&next(1)?[0]);
+            }
+            "--proc" => {
+                let dest = PathBuf::from(&next(1)?[0]);
+                let proc = config::ProcOptionsBuilder::default()
+                    .destination(dest)
+                    .build()
+                    .map_err(|e| e.to_string())?;
+                wrap.proc_mount(proc);
+            }
+            "--dev" => {
+                if next(1)?[0] != OsStr::new("/dev") {
+                    return Err("--dev is only supported on /dev".into());
+                }
+                wrap.dev(config::DevConfig::default());
+            }
+            "--tmpfs" => {
+                let dest = PathBuf::from(&next(1)?[0]);
+                let mut options = vec![format!("mode={:o}", opts.perms.take().unwrap_or(0o755))];
+                if let Some(size) = opts.size.take() {
+                    options.push(format!("size={}", size));
+                }
+                let mnt = config::MountBuilder::default()
+                    .destination(dest)
+                    .typ(Some("tmpfs".into()))
+                    .source(Some("tmpfs".into()))
+                    .options(Some(options))
+                    .build()
+                    .map_err(|e| e.to_string())?;
+                wrap.mount(mnt);
+            }
+            "--dir" => {
+                wrap.dir(&next(1)?[0], opts.perms.take().unwrap_or(0o755));
+            }
+            "--file" | "--bind-data" | "--ro-bind-data" => {
+                let a = next(2)?;
+                let mut data = Vec::new();
+                std::fs::File::from(parse_fd(&a[0])?)
+                    .read_to_end(&mut data)
+                    .map_err(|e| format!("{}: {}", opt, e))?;
+                let mode = opts.perms.take().unwrap_or(match opt {
+                    "--file" => 0o666,
+                    _ => 0o600,
+                });
+                wrap.file(&a[1], data, mode);
+                if opt == "--ro-bind-data" {
+                    wrap.remount_ro(&a[1]);
+                }
+            }
+            "--symlink" => {
+                let a = next(2)?;
+                wrap.symlink(&a[0], &a[1]);
+            }
+            "--chmod" => {
+                let a = next(2)?;
+                opts.chmods
+                    .push((PathBuf::from(&a[1]), parse_num(&a[0], 8)?));
+            }
+            "--perms" => opts.perms = Some(parse_num(&next(1)?[0], 8)?),
+            "--size" => opts.size = Some(parse_num(&next(1)?[0], 10)?),
+            "--die-with-parent" => {
+                wrap.die_with_parent(true);
+            }
+            "--new-session" => opts.new_session = true,
+            opt if UNSUPPORTED.contains(&opt) => {
+                return Err(format!("{} is not supported", opt));
+            }
+            opt => return Err(format!("Unknown option {}", opt)),
+        }
+    }
+    Ok(args.into())
+}
+
+fn to_string(s: &OsStr) -> Result<String, String> {
+    s.to_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| format!("{:?} is not valid UTF-8", s))
+}
+
+fn parse_num<T: TryFrom<u64>>(s: &OsStr, radix: u32) -> Result<T, String> {
+    let s = to_string(s)?;
+    u64::from_str_radix(&s, radix)
+        .ok()
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or_else(|| format!("Invalid number {}", s))
+}
+
+fn parse_fd(s: &OsStr) -> Result<OwnedFd, String> {
+    let fd: i32 = parse_num(s, 10)?;
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        return Err(format!("Invalid fd {}", fd));
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
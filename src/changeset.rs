@@ -0,0 +1,177 @@
+/*!
+Changes a sandboxed process made to an overlayfs root.
+
+When the container root is an overlay with a persistent upper layer
+(see [`crate::Wrap::overlay_root()`]), everything the process wrote ends
+up in the upper directory. [`Changeset`] reads it back after the child
+exited, and can export it as a tar archive that follows the whiteout
+convention of OCI image layers.
+*/
+use crate::error::Error;
+use std::{
+    ffi::OsString,
+    fs,
+    io::Write,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+/// Prefix of whiteout files in OCI image layers.
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Marker of opaque directories in OCI image layers.
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The path does not exist in any lower layer.
+    Added,
+    /// The path exists in a lower layer and was replaced or changed.
+    Modified,
+    /// The path exists in a lower layer and was removed.
+    Deleted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A single change, with a path relative to the container root.
+pub struct Change {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    /// The directory hides everything below it in lower layers.
+    pub opaque: bool,
+}
+
+/// All changes recorded in an overlayfs upper directory.
+pub struct Changeset {
+    upper: PathBuf,
+    changes: Vec<Change>,
+}
+
+impl Changeset {
+    /// Read the changes from `upper`, classifying them against `lower`.
+    ///
+    /// `lower` is the list of lower layers, in the same order as given
+    /// to overlayfs.
+    pub fn from_upper<P: AsRef<Path>>(upper: &Path, lower: &[P]) -> Result<Self, Error> {
+        let mut s = Self {
+            upper: upper.to_path_buf(),
+            changes: Vec::new(),
+        };
+        s.walk(Path::new(""), lower)?;
+        Ok(s)
+    }
+
+    pub fn changes(&self) -> &[Change] {
+        &self.changes
+    }
+
+    /// Directory the changes are read from.
+    pub fn upper(&self) -> &Path {
+        &self.upper
+    }
+
+    fn walk<P: AsRef<Path>>(&mut self, rel: &Path, lower: &[P]) -> Result<(), Error> {
+        let mut entries = fs::read_dir(self.upper.join(rel))?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let path = rel.join(entry.file_name());
+            let meta = fs::symlink_metadata(entry.path())?;
+            let in_lower = lower
+                .iter()
+                .any(|l| fs::symlink_metadata(l.as_ref().join(&path)).is_ok());
+
+            if is_whiteout(&entry.path(), &meta) {
+                self.changes.push(Change {
+                    path,
+                    kind: ChangeKind::Deleted,
+                    opaque: false,
+                });
+                continue;
+            }
+
+            let kind = match in_lower {
+                true => ChangeKind::Modified,
+                false => ChangeKind::Added,
+            };
+            if meta.is_dir() {
+                let opaque = is_opaque(&entry.path());
+                // Directories are copied up whenever something below them
+                // changes, only report them if they are new or opaque.
+                if kind == ChangeKind::Added || opaque {
+                    self.changes.push(Change {
+                        path: path.clone(),
+                        kind,
+                        opaque,
+                    });
+                }
+                self.walk(&path, lower)?;
+            } else {
+                self.changes.push(Change {
+                    path,
+                    kind,
+                    opaque: false,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the changes as a tar archive.
+    ///
+    /// Deleted paths become `.wh.<name>` entries and opaque directories
+    /// contain a `.wh..wh..opq` entry, like OCI image layers.
+    pub fn write_tar<W: Write>(&self, w: W) -> Result<(), Error> {
+        let mut builder = tar::Builder::new(w);
+        builder.follow_symlinks(false);
+        for change in &self.changes {
+            match change.kind {
+                ChangeKind::Deleted => {
+                    let mut name = OsString::from(WHITEOUT_PREFIX);
+                    name.push(change.path.file_name().unwrap_or_default());
+                    let path = change.path.with_file_name(name);
+                    append_empty(&mut builder, &path)?;
+                }
+                _ => {
+                    builder.append_path_with_name(self.upper.join(&change.path), &change.path)?;
+                    if change.opaque {
+                        append_empty(&mut builder, &change.path.join(WHITEOUT_OPAQUE))?;
+                    }
+                }
+            }
+        }
+        builder.finish()?;
+        Ok(())
+    }
+}
+
+fn append_empty<W: Write>(builder: &mut tar::Builder<W>, path: &Path) -> Result<(), Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(0);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, path, std::io::empty())?;
+    Ok(())
+}
+
+/// Overlayfs whiteouts are character devices with device number 0/0.
+fn is_whiteout(path: &Path, meta: &fs::Metadata) -> bool {
+    if meta.file_type().is_char_device() && meta.rdev() == 0 {
+        return true;
+    }
+    // Since Linux 6.7 whiteouts can also be marked with an xattr.
+    meta.is_file() && meta.len() == 0 && has_overlay_xattr(path, "whiteout", |_| true)
+}
+
+/// Opaque directories are marked with the `overlay.opaque` xattr.
+fn is_opaque(path: &Path) -> bool {
+    has_overlay_xattr(path, "opaque", |v| v == b"y")
+}
+
+/// Check both the `trusted.` and the `user.` namespace, the latter is
+/// used by rootless overlayfs mounted with `userxattr`.
+fn has_overlay_xattr(path: &Path, name: &str, check: impl Fn(&[u8]) -> bool) -> bool {
+    ["trusted", "user"].iter().any(|ns| {
+        let mut buf = [0u8; 8];
+        let attr = format!("{}.overlay.{}", ns, name);
+        matches!(rustix::fs::lgetxattr(path, attr.as_str(), &mut buf), Ok(n) if check(&buf[..n]))
+    })
+}
@@ -1,8 +1,13 @@
 use getset::{CopyGetters, Getters, Setters};
 use std::collections::HashMap;
 use std::ffi::OsString;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::OwnedFd;
 use std::path::PathBuf;
+use std::sync::Arc;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 #[derive(Default, Clone, Copy)]
 pub enum NamespaceType {
     Mount,
@@ -16,14 +21,31 @@ pub enum NamespaceType {
     Time,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 #[derive(Default, Clone, Copy)]
 pub enum NamespaceItem {
     #[default]
     None,
     Unshare,
+    /// Namespaces entered by fd cannot be serialized, an fd number means
+    /// nothing in another process.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "serialize_ns_fd", skip_deserializing)
+    )]
     Enter(std::os::fd::RawFd),
 }
 
+#[cfg(feature = "serde")]
+fn serialize_ns_fd<S: serde::Serializer>(_: &std::os::fd::RawFd, _: S) -> Result<S::Ok, S::Error> {
+    Err(serde::ser::Error::custom(
+        "namespaces entered by fd cannot be serialized",
+    ))
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 #[derive(Getters, Setters, CopyGetters, Default, Clone)]
 pub struct NamespaceSet {
     pub(crate) user: NamespaceItem,
@@ -33,32 +55,70 @@ pub struct NamespaceSet {
     pub(crate) ipc: NamespaceItem,
     pub(crate) pid: NamespaceItem,
     pub(crate) network: NamespaceItem,
-    //pub(crate) time: NamespaceItem,
+    /// Not supported yet, [`crate::Wrap::validate()`] reports it.
+    pub(crate) time: NamespaceItem,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 #[derive(Builder, Getters, Setters, CopyGetters, Default, Clone)]
 pub struct Root {
     #[getset(get = "pub", set = "pub")]
-    path: PathBuf,
+    /// Path is the absolute path to the container's root filesystem.
+    pub(crate) path: PathBuf,
 
     #[getset(get = "pub", set = "pub")]
-    readonly: Option<bool>,
+    /// Readonly makes the root filesystem for the container readonly
+    /// before the process is executed.
+    pub(crate) readonly: Option<bool>,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 #[derive(Builder, Getters, Setters, CopyGetters, Default, Clone)]
 pub struct Mount {
     #[getset(get = "pub", set = "pub")]
-    destination: PathBuf,
+    pub(crate) destination: PathBuf,
     // Path values for bind mounts are either absolute or relative to the
     // bundle. A mount is a bind mount if it has either bind or rbind in the options.
     #[getset(get = "pub", set = "pub")]
-    typ: Option<String>,
+    #[cfg_attr(feature = "serde", serde(alias = "type"))]
+    pub(crate) typ: Option<String>,
     #[getset(get = "pub", set = "pub")]
-    source: Option<PathBuf>,
+    pub(crate) source: Option<PathBuf>,
+    #[getset(get = "pub", set = "pub")]
+    pub(crate) options: Option<Vec<String>>,
+
     #[getset(get = "pub", set = "pub")]
-    options: Option<Vec<String>>,
+    #[builder(default)]
+    /// UIDMappings specifies the user mappings of an idmapped mount.
+    ///
+    /// `host_id` is the owner on the source filesystem, `container_id`
+    /// is the owner seen inside the container.
+    pub(crate) uid_mappings: Vec<IdMap>,
+
+    #[getset(get = "pub", set = "pub")]
+    #[builder(default)]
+    /// GIDMappings specifies the group mappings of an idmapped mount.
+    pub(crate) gid_mappings: Vec<IdMap>,
+}
+
+impl Mount {
+    /// A mount is a bind mount if it has either bind or rbind in the options.
+    pub fn is_bind(&self) -> bool {
+        self.options
+            .iter()
+            .flatten()
+            .any(|o| o == "bind" || o == "rbind")
+    }
+
+    pub fn is_idmapped(&self) -> bool {
+        !(self.uid_mappings.is_empty() && self.gid_mappings.is_empty())
+    }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 #[derive(Builder, Getters, Setters, CopyGetters, Default, Clone)]
 /// Process contains information to start a specific application inside the
 /// container.
@@ -68,16 +128,19 @@ pub struct Process {
     pub(crate) user: Option<User>,
 
     #[getset(get = "pub", set = "pub")]
+    #[cfg_attr(feature = "serde", serde(with = "serde_os::string"))]
     pub(crate) bin: OsString,
 
     #[getset(get = "pub", set = "pub")]
     /// Args specifies the arguments for the application to
     /// execute.
+    #[cfg_attr(feature = "serde", serde(with = "serde_os::strings"))]
     pub(crate) args: Vec<OsString>,
 
     #[getset(get = "pub", set = "pub")]
     /// Env populates the process environment for the process.
-    pub(crate) env: HashMap<OsString,EnvVarItem>,
+    #[cfg_attr(feature = "serde", serde(with = "serde_os::env"))]
+    pub(crate) env: HashMap<OsString, EnvVarItem>,
 
     #[getset(get = "pub", set = "pub")]
     /// Prevent the spawned child process from inheriting
@@ -90,23 +153,29 @@ pub struct Process {
     pub(crate) cwd: Option<PathBuf>,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 #[derive(Clone)]
 pub enum EnvVarItem {
-    Set(OsString),
+    Set(#[cfg_attr(feature = "serde", serde(with = "serde_os::string"))] OsString),
     Clean,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 #[derive(Builder, Getters, Setters, CopyGetters, Default, Clone)]
 pub struct User {
     #[getset(get_copy = "pub", set = "pub")]
     /// UID is the user id.
-    uid: u32,
+    pub(crate) uid: u32,
 
     #[getset(get_copy = "pub", set = "pub")]
     /// GID is the group id.
-    gid: u32,
+    pub(crate) gid: u32,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 #[derive(Builder, Getters, Setters, CopyGetters, Default, Clone)]
 /// LinuxIDMapping specifies UID/GID mappings.
 pub struct IdMap {
@@ -123,8 +192,483 @@ pub struct IdMap {
     pub(crate) size: u32,
 }
 
+/// Network setup done inside a new network namespace.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[derive(Default, Clone)]
+pub enum NetworkConfig {
+    /// Leave the network namespace as the kernel created it.
+    #[default]
+    None,
+    /// Bring up the loopback interface, so that services can be
+    /// bound to `127.0.0.1` and `::1`.
+    LoopbackOnly,
+    /// Connect the namespace to the host with a `veth(4)` pair.
+    ///
+    /// Loopback is brought up as well.
+    /// Creating the pair requires `CAP_NET_ADMIN` in the parent.
+    Veth(VethConfig),
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[derive(Builder, Getters, Setters, CopyGetters, Default, Clone)]
+/// Veth specifies a `veth(4)` pair between the host and the container.
+pub struct VethConfig {
+    #[getset(get = "pub", set = "pub")]
+    /// Name of the end that stays on the host.
+    pub(crate) host_name: String,
+
+    #[getset(get = "pub", set = "pub")]
+    /// Name of the end that is moved into the container.
+    pub(crate) container_name: String,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[builder(default)]
+    /// Address and prefix length of the host end.
+    pub(crate) host_address: Option<(IpAddr, u8)>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[builder(default)]
+    /// Address and prefix length of the container end.
+    pub(crate) container_address: Option<(IpAddr, u8)>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[builder(default)]
+    /// Default gateway inside the container, usually the host address.
+    pub(crate) gateway: Option<IpAddr>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[builder(default)]
+    /// MTU of both ends.
+    pub(crate) mtu: Option<u32>,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Getters, CopyGetters, Clone)]
+/// PortForward connects a host address to a port on the loopback
+/// interface of the container.
+pub struct PortForward {
+    #[getset(get_copy = "pub")]
+    /// Address the parent listens on.
+    pub(crate) host_address: SocketAddr,
+
+    #[getset(get_copy = "pub")]
+    /// Port inside the container network namespace.
+    pub(crate) container_port: u16,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[derive(Builder, Getters, Setters, Default, Clone)]
+/// EtcFiles specifies the files generated under `/etc` in the container.
+///
+/// `passwd` and `group` are derived from the id maps, `hosts` from the
+/// hostname, and `resolv.conf` from the fields below.
+pub struct EtcFiles {
+    #[getset(get = "pub", set = "pub")]
+    #[builder(default)]
+    /// Nameservers written to `/etc/resolv.conf`.
+    pub(crate) nameservers: Vec<IpAddr>,
+
+    #[getset(get = "pub", set = "pub")]
+    #[builder(default)]
+    /// Search domains written to `/etc/resolv.conf`.
+    pub(crate) search: Vec<String>,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[derive(Builder, Getters, Setters, Default, Clone)]
+/// Overlay specifies an overlayfs used as the container root.
+pub struct Overlay {
+    #[getset(get = "pub", set = "pub")]
+    /// Read-only lower layers, the first one is the top-most.
+    pub(crate) lower: Vec<PathBuf>,
+
+    #[getset(get = "pub", set = "pub")]
+    #[builder(default)]
+    /// Directory that keeps the `upper` and `work` directories of
+    /// overlayfs across runs. A tmpfs is used if this is `None`,
+    /// and all changes are lost when the container exits.
+    pub(crate) upper: Option<PathBuf>,
+}
+
+/// Propagation of mount events between the host and the container.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Propagation {
+    /// No events are propagated either way.
+    Private,
+    /// Mounts on the host show up in the container, not the other way.
+    #[default]
+    Slave,
+    /// Like `Slave`, and mounts are shared only within the container's
+    /// mount namespace and its children, not with the host.
+    Shared,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[derive(Builder, Getters, Setters, CopyGetters, Clone)]
+/// SandboxMnt specifies the tmpfs used as root dir by
+/// [`crate::Wrap::sandbox_mnt()`], and as staging area of an overlay root.
+pub struct SandboxMnt {
+    #[getset(get_copy = "pub", set = "pub")]
+    #[builder(default)]
+    /// Size limit of the tmpfs in bytes, half of the RAM by default.
+    pub(crate) size: Option<u64>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[builder(default)]
+    /// Permissions of the root dir, `1777` by default.
+    pub(crate) mode: Option<u32>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[builder(default)]
+    /// Maximum number of inodes of the tmpfs.
+    pub(crate) nr_inodes: Option<u64>,
+
+    #[getset(get = "pub", set = "pub")]
+    #[builder(default = "PathBuf::from(\"/tmp\")")]
+    /// Host directory the tmpfs is mounted on before `pivot_root(2)`.
+    /// It is only hidden inside the mount namespace of the container.
+    pub(crate) staging: PathBuf,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[builder(default)]
+    /// Propagation applied to all mounts of the container.
+    pub(crate) propagation: Propagation,
+}
+
+impl Default for SandboxMnt {
+    fn default() -> Self {
+        Self {
+            size: None,
+            mode: None,
+            nr_inodes: None,
+            staging: PathBuf::from("/tmp"),
+            propagation: Propagation::default(),
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[derive(Builder, Getters, Setters, CopyGetters, Default, Clone)]
+/// DevConfig specifies the minimal `/dev` mounted by [`crate::Wrap::dev()`].
+///
+/// `null`, `zero`, `full`, `random`, `urandom` and `tty` are always
+/// provided.
+pub struct DevConfig {
+    #[getset(get_copy = "pub", set = "pub")]
+    #[builder(default)]
+    /// Also provide `/dev/fuse`.
+    pub(crate) fuse: bool,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[builder(default)]
+    /// Also provide `/dev/kvm`.
+    pub(crate) kvm: bool,
+}
+
+/// A step of building the filesystem of the container. Steps run in the
+/// order they were added, after the root dir is set up.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[derive(Clone)]
+pub(crate) enum FsOp {
+    /// Mount the entry of the mount list with this index.
+    Mount(usize),
+    /// Create a file, replacing anything that exists at `dest`.
+    File {
+        dest: PathBuf,
+        data: FileData,
+        mode: u32,
+    },
+    /// Create a symlink at `link` pointing to `target`.
+    Symlink { target: PathBuf, link: PathBuf },
+    /// Create a directory and its missing parents.
+    Dir { path: PathBuf, mode: u32 },
+    /// Make the mount at `path` read-only, without the mounts below it.
+    RemountRo { path: PathBuf },
+}
+
+/// Contents of a file created by [`FsOp::File`].
+#[derive(Clone)]
+pub(crate) enum FileData {
+    Bytes(Arc<[u8]>),
+    /// Read until EOF in the child.
+    Fd(Arc<OwnedFd>),
+}
+
+/// Files are stored as their contents, those read from an fd cannot be
+/// serialized.
+#[cfg(feature = "serde")]
+impl serde::Serialize for FileData {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            FileData::Bytes(data) => serializer.collect_seq(data.iter()),
+            FileData::Fd(_) => Err(serde::ser::Error::custom(
+                "files read from an fd cannot be serialized",
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for FileData {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data: Vec<u8> = serde::Deserialize::deserialize(deserializer)?;
+        Ok(FileData::Bytes(data.into()))
+    }
+}
+
+/// Who can see the processes of other users in `/proc`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HidePid {
+    /// Everyone can see and read all `/proc/<pid>` directories.
+    #[default]
+    Off = 0,
+    /// Other users' `/proc/<pid>` directories are visible but not readable.
+    NoAccess = 1,
+    /// Other users' processes are not visible at all.
+    Invisible = 2,
+    /// Only processes that could be traced are visible.
+    NotPtraceable = 4,
+}
+
+impl HidePid {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            HidePid::Off => "off",
+            HidePid::NoAccess => "noaccess",
+            HidePid::Invisible => "invisible",
+            HidePid::NotPtraceable => "ptraceable",
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[derive(Builder, Getters, Setters, CopyGetters, Clone)]
+/// ProcOptions specifies the procfs mounted by [`crate::Wrap::proc_mount()`].
+pub struct ProcOptions {
+    #[getset(get_copy = "pub", set = "pub")]
+    #[builder(default)]
+    /// The `hidepid=` option of procfs.
+    pub(crate) hidepid: Option<HidePid>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[builder(default)]
+    /// Group that can see all processes despite `hidepid`.
+    pub(crate) gid: Option<u32>,
+
+    #[getset(get_copy = "pub", set = "pub")]
+    #[builder(default)]
+    /// Only show the process directories, with `subset=pid`.
+    /// This hides `/proc/sys` and other system-wide files.
+    pub(crate) subset_pid: bool,
+
+    #[getset(get = "pub", set = "pub")]
+    #[builder(default = "PathBuf::from(\"/proc\")")]
+    /// Where procfs is mounted inside the container.
+    pub(crate) destination: PathBuf,
+}
+
+impl Default for ProcOptions {
+    fn default() -> Self {
+        Self {
+            hidepid: None,
+            gid: None,
+            subset_pid: false,
+            destination: PathBuf::from("/proc"),
+        }
+    }
+}
+
+/// Paths hidden by default in runc containers.
+pub const DEFAULT_MASKED_PATHS: &[&str] = &[
+    "/proc/acpi",
+    "/proc/asound",
+    "/proc/interrupts",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/timer_list",
+    "/proc/timer_stats",
+    "/proc/sched_debug",
+    "/proc/scsi",
+    "/sys/firmware",
+    "/sys/devices/virtual/powercap",
+];
+
+/// Paths that are read-only by default in runc containers.
+pub const DEFAULT_READONLY_PATHS: &[&str] = &[
+    "/proc/bus",
+    "/proc/fs",
+    "/proc/irq",
+    "/proc/sys",
+    "/proc/sysrq-trigger",
+];
+
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum IdMapPreset {
     Root,
     Current,
     Auto,
 }
+
+#[derive(Getters, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+/// WrapConfig is everything a [`crate::Wrap`] does, except the callbacks.
+///
+/// It is taken with [`crate::Wrap::config()`] and turned back into a
+/// `Wrap` with [`crate::Wrap::from_config()`]. Namespaces to enter are
+/// kept as fd numbers, which only mean something in the same process,
+/// so a config with them cannot be serialized.
+pub struct WrapConfig {
+    #[getset(get = "pub")]
+    pub(crate) process: Process,
+    #[getset(get = "pub")]
+    pub(crate) root: Option<Root>,
+    #[getset(get = "pub")]
+    pub(crate) mounts: Vec<Mount>,
+    #[getset(get = "pub")]
+    pub(crate) uid_maps: Vec<IdMap>,
+    #[getset(get = "pub")]
+    pub(crate) gid_maps: Vec<IdMap>,
+    #[getset(get = "pub")]
+    pub(crate) namespace_nsenter: NamespaceSet,
+    #[getset(get = "pub")]
+    pub(crate) namespace_unshare: NamespaceSet,
+    #[getset(get = "pub")]
+    pub(crate) sandbox_mnt: bool,
+    #[getset(get = "pub")]
+    pub(crate) sandbox: SandboxMnt,
+    #[getset(get = "pub")]
+    pub(crate) network: NetworkConfig,
+    #[getset(get = "pub")]
+    pub(crate) port_forwards: Vec<PortForward>,
+    #[getset(get = "pub")]
+    pub(crate) hostname: Option<String>,
+    #[getset(get = "pub")]
+    pub(crate) etc_files: Option<EtcFiles>,
+    #[getset(get = "pub")]
+    pub(crate) overlay: Option<Overlay>,
+    #[getset(get = "pub")]
+    pub(crate) masked_paths: Vec<PathBuf>,
+    #[getset(get = "pub")]
+    pub(crate) readonly_paths: Vec<PathBuf>,
+    #[getset(get = "pub")]
+    pub(crate) proc: Option<ProcOptions>,
+    #[getset(get = "pub")]
+    pub(crate) dev: Option<DevConfig>,
+    /// Mounts, files, symlinks and dirs in the order they were added.
+    pub(crate) fs_ops: Vec<FsOp>,
+    #[getset(get = "pub")]
+    pub(crate) die_with_parent: bool,
+}
+
+#[cfg(feature = "serde")]
+impl WrapConfig {
+    /// Serialize to pretty-printed JSON.
+    pub fn to_json(&self) -> Result<String, crate::error::Error> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| crate::error::Error::InvalidConfig(e.to_string()))
+    }
+
+    /// Parse JSON written by [`Self::to_json()`], missing fields are
+    /// left at their defaults.
+    pub fn from_json(json: &str) -> Result<Self, crate::error::Error> {
+        serde_json::from_str(json).map_err(|e| crate::error::Error::InvalidConfig(e.to_string()))
+    }
+
+    /// Serialize to TOML.
+    pub fn to_toml(&self) -> Result<String, crate::error::Error> {
+        toml::to_string(self).map_err(|e| crate::error::Error::InvalidConfig(e.to_string()))
+    }
+
+    /// Parse TOML written by [`Self::to_toml()`], missing fields are
+    /// left at their defaults.
+    pub fn from_toml(s: &str) -> Result<Self, crate::error::Error> {
+        toml::from_str(s).map_err(|e| crate::error::Error::InvalidConfig(e.to_string()))
+    }
+}
+
+/// `OsString`s are stored as strings, and must be valid UTF-8 to be
+/// serialized.
+#[cfg(feature = "serde")]
+mod serde_os {
+    use super::EnvVarItem;
+    use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::{BTreeMap, HashMap};
+    use std::ffi::{OsStr, OsString};
+
+    fn to_str<E: ser::Error>(s: &OsStr) -> Result<&str, E> {
+        s.to_str()
+            .ok_or_else(|| E::custom(format!("{:?} is not valid UTF-8", s)))
+    }
+
+    pub(super) mod string {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(s: &OsString, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(to_str(s)?)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<OsString, D::Error> {
+            String::deserialize(deserializer).map(OsString::from)
+        }
+    }
+
+    pub(super) mod strings {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(v: &[OsString], serializer: S) -> Result<S::Ok, S::Error> {
+            let v = v.iter().map(|s| to_str(s)).collect::<Result<Vec<_>, _>>()?;
+            v.serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<OsString>, D::Error> {
+            Vec::<String>::deserialize(deserializer)
+                .map(|v| v.into_iter().map(Into::into).collect())
+        }
+    }
+
+    /// Sorted by name, so that equal environments serialize the same.
+    pub(super) mod env {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            env: &HashMap<OsString, EnvVarItem>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let env = env
+                .iter()
+                .map(|(k, v)| Ok((to_str(k)?, v)))
+                .collect::<Result<BTreeMap<_, _>, S::Error>>()?;
+            env.serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<HashMap<OsString, EnvVarItem>, D::Error> {
+            let env = HashMap::<String, EnvVarItem>::deserialize(deserializer)?;
+            if env.keys().any(|k| k.is_empty() || k.contains('=')) {
+                return Err(de::Error::custom("invalid environment variable name"));
+            }
+            Ok(env.into_iter().map(|(k, v)| (k.into(), v)).collect())
+        }
+    }
+}
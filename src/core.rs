@@ -4,7 +4,11 @@ use std::{
     collections::VecDeque,
     ffi::{OsStr, OsString},
     fs::OpenOptions,
+    net::TcpListener,
+    os::fd::{AsRawFd, OwnedFd, RawFd},
     os::unix::prelude::OsStrExt,
+    path::PathBuf,
+    sync::atomic::{AtomicI32, Ordering},
 };
 
 use crate::util::CloneFlags;
@@ -14,30 +18,253 @@ use crate::{config, util, Child, Error};
 /// Default stack size
 ///
 /// https://wiki.musl-libc.org/functional-differences-from-glibc.html
-const STACK_SIZE: usize = 122880;
+pub(crate) const STACK_SIZE: usize = 122880;
+
+/// First process of the PID namespace, see [`WrapInner::enter_pid_ns()`].
+static CHILD_PID: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(sig: libc::c_int) {
+    unsafe { libc::kill(CHILD_PID.load(Ordering::Relaxed), sig) };
+}
 
 /// Boxed closure to execute in child process
 pub type WrapCbBox<'a> = Box<dyn FnOnce() -> isize + 'a>;
 
+/// Boxed closure to execute in child process, whose serialized result is
+/// sent to the parent
+pub(crate) type WrapResultCbBox<'a> = Box<dyn FnOnce() -> Vec<u8> + 'a>;
+
+/// Boxed closure to execute in child process before its root is changed
+pub type WrapSetupCbBox<'a> = Box<dyn FnOnce() -> Result<(), Error> + 'a>;
+
+/// Boxed closure to execute in parent process with the pid of the child
+pub type WrapParentCbBox<'a> = Box<dyn FnOnce(u32) -> Result<(), Error> + 'a>;
+
 impl crate::Wrap<'_> {
     pub(crate) fn spawn_inner(mut wrap: WrapInner) -> Result<Child, Error> {
         let mut p: Box<[u8; STACK_SIZE]> = Box::new([0; STACK_SIZE]);
 
-        let pid = match unsafe {
+        let (report_r, report_w) = pipe_cloexec()?;
+        wrap.report = Some(report_w);
+        wrap.parent_fds.push(report_r.as_raw_fd());
+
+        let result_r = match wrap.result_callback {
+            Some(_) => {
+                let (result_r, result_w) = pipe_cloexec()?;
+                wrap.result = Some(result_w);
+                wrap.parent_fds.push(result_r.as_raw_fd());
+                Some(result_r)
+            }
+            None => None,
+        };
+
+        let parent_sync = if wrap.needs_parent_setup() {
+            let (parent, child) = sync_pair()?;
+            wrap.parent_fds.push(parent.ready.as_raw_fd());
+            wrap.parent_fds.push(parent.resume.as_raw_fd());
+            wrap.sync = Some(child);
+            Some(parent)
+        } else {
+            None
+        };
+        let setup = ParentSetup {
+            callbacks: std::mem::take(&mut wrap.parent_callbacks),
+            network: wrap.network.clone(),
+            forwards: wrap.bind_forwards()?,
+            user_ns: !matches!(wrap.namespace_unshare.user, config::NamespaceItem::None),
+        };
+        for (listener, _) in &setup.forwards {
+            wrap.parent_fds.push(listener.as_raw_fd());
+        }
+        let overlay = wrap.overlay.clone();
+        wrap.prepare_mount_trees()?;
+
+        let pid = unsafe {
             crate::util::clone(
                 Box::new(move || -> isize { wrap.run_child() }),
                 &mut *p,
                 util::CloneFlags::empty(),
                 Some(libc::SIGCHLD),
             )
-        } {
-            Ok(it) => it,
-            Err(e) => return Err(e),
-        };
+        }?;
 
-        Ok(Child {
+        let mut child = Child {
             pid: unsafe { rustix::process::Pid::from_raw_unchecked(pid.try_into().unwrap()) },
-        })
+            forwarders: Vec::new(),
+            overlay,
+            result: result_r,
+        };
+
+        if let Some(sync) = parent_sync {
+            if let Err(e) = setup.run(&sync, &mut child, pid) {
+                // The child will see EOF on the barrier and exit.
+                drop(sync);
+                child.forwarders.clear();
+                let e = read_report(&report_r).err().unwrap_or(e);
+                let _ = child.wait();
+                return Err(e);
+            }
+            sync.resume()?;
+        }
+
+        if let Err(e) = read_report(&report_r) {
+            let _ = child.wait();
+            return Err(e);
+        }
+
+        Ok(child)
+    }
+}
+
+fn pipe_cloexec() -> Result<(OwnedFd, OwnedFd), Error> {
+    use rustix::pipe::{pipe_with, PipeFlags};
+    Ok(pipe_with(PipeFlags::CLOEXEC)?)
+}
+
+/// Wait for the child to report the result of its setup.
+///
+/// The report is a tag byte, `0` for success, or `1` followed by the
+/// errno and the name of the failed step.
+fn read_report(fd: &OwnedFd) -> Result<(), Error> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 256];
+    loop {
+        match rustix::io::read(fd, &mut chunk) {
+            Ok(0) => break,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(rustix::io::Errno::INTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+        if buf[0] == 0 {
+            return Ok(());
+        }
+    }
+    match buf.first() {
+        Some(1) if buf.len() >= 5 => Err(Error::SetupFailed(
+            String::from_utf8_lossy(&buf[5..]).into_owned(),
+            i32::from_ne_bytes(buf[1..5].try_into().unwrap()),
+        )),
+        _ => Err(Error::SetupFailed("child exited".into(), libc::EPIPE)),
+    }
+}
+
+/// Report the result of the setup to the parent, see [`read_report`].
+fn write_report(fd: OwnedFd, res: &Result<(), Error>) {
+    let buf = match res {
+        Ok(()) => vec![0],
+        Err(e) => {
+            let (step, errno) = match e {
+                Error::SetupFailed(step, errno) => (step.clone(), *errno),
+                Error::OsErrno(errno) => ("unknown".into(), *errno),
+                e => (e.to_string(), 0),
+            };
+            let mut buf = vec![1];
+            buf.extend_from_slice(&errno.to_ne_bytes());
+            buf.extend_from_slice(step.as_bytes());
+            buf
+        }
+    };
+    let _ = rustix::io::write(fd, &buf);
+}
+
+/// Write all of `buf`, errors are seen by the parent as a short read.
+fn write_all(fd: &OwnedFd, mut buf: &[u8]) {
+    while !buf.is_empty() {
+        match rustix::io::write(fd, buf) {
+            Ok(0) => return,
+            Ok(n) => buf = &buf[n..],
+            Err(rustix::io::Errno::INTR) => continue,
+            Err(_) => return,
+        }
+    }
+}
+
+/// Work the parent does while the child waits at the barrier.
+struct ParentSetup<'a> {
+    callbacks: VecDeque<WrapParentCbBox<'a>>,
+    network: config::NetworkConfig,
+    forwards: Vec<(TcpListener, u16)>,
+    user_ns: bool,
+}
+
+impl ParentSetup<'_> {
+    fn run(self, sync: &ParentSync, child: &mut Child, pid: u32) -> Result<(), Error> {
+        sync.wait_ready()?;
+        if let config::NetworkConfig::Veth(veth) = &self.network {
+            crate::net::veth_host_setup(veth, pid)?;
+        }
+        for cb in self.callbacks {
+            cb(pid)?;
+        }
+        for (listener, port) in self.forwards {
+            let forwarder = crate::net::spawn_forwarder(listener, port, pid, self.user_ns)?;
+            child.forwarders.push(forwarder);
+        }
+        Ok(())
+    }
+}
+
+/// Parent side of the setup barrier.
+pub(crate) struct ParentSync {
+    ready: OwnedFd,
+    resume: OwnedFd,
+}
+
+/// Child side of the setup barrier.
+pub(crate) struct ChildSync {
+    ready: OwnedFd,
+    resume: OwnedFd,
+}
+
+/// Create the pipes used to hold the child until the parent
+/// finished its part of the setup.
+pub(crate) fn sync_pair() -> Result<(ParentSync, ChildSync), Error> {
+    let (ready_r, ready_w) = pipe_cloexec()?;
+    let (resume_r, resume_w) = pipe_cloexec()?;
+    Ok((
+        ParentSync {
+            ready: ready_r,
+            resume: resume_w,
+        },
+        ChildSync {
+            ready: ready_w,
+            resume: resume_r,
+        },
+    ))
+}
+
+fn read_byte(fd: &OwnedFd) -> Result<(), Error> {
+    let mut buf = [0u8; 1];
+    match rustix::io::read(fd, &mut buf) {
+        Ok(1) => Ok(()),
+        Ok(_) => Err(Error::OsErrno(libc::EPIPE)),
+        Err(e) => Err(Error::OsErrno(e.raw_os_error())),
+    }
+}
+
+fn write_byte(fd: &OwnedFd) -> Result<(), Error> {
+    rustix::io::write(fd, &[0])
+        .map(|_| ())
+        .map_err(|e| Error::OsErrno(e.raw_os_error()))
+}
+
+impl ParentSync {
+    /// Wait until the child has entered its namespaces.
+    pub(crate) fn wait_ready(&self) -> Result<(), Error> {
+        read_byte(&self.ready)
+    }
+
+    /// Let the child continue.
+    pub(crate) fn resume(self) -> Result<(), Error> {
+        write_byte(&self.resume)
+    }
+}
+
+impl ChildSync {
+    /// Tell the parent that namespaces are set up, then wait for it.
+    pub(crate) fn barrier(&self) -> Result<(), Error> {
+        write_byte(&self.ready)?;
+        read_byte(&self.resume)
     }
 }
 
@@ -50,51 +277,500 @@ pub(crate) struct WrapInner<'a> {
     pub(crate) uid_maps: Vec<config::IdMap>,
     pub(crate) gid_maps: Vec<config::IdMap>,
     pub(crate) callbacks: VecDeque<WrapCbBox<'a>>,
+    pub(crate) setup_callbacks: VecDeque<WrapSetupCbBox<'a>>,
+    pub(crate) parent_callbacks: VecDeque<WrapParentCbBox<'a>>,
+    pub(crate) result_callback: Option<WrapResultCbBox<'a>>,
 
     pub(crate) namespace_nsenter: config::NamespaceSet,
     pub(crate) namespace_unshare: config::NamespaceSet,
 
     pub(crate) sandbox_mnt: bool,
+    pub(crate) sandbox: config::SandboxMnt,
+    pub(crate) masked_paths: Vec<PathBuf>,
+    pub(crate) readonly_paths: Vec<PathBuf>,
+    pub(crate) proc: Option<config::ProcOptions>,
+    pub(crate) dev: Option<config::DevConfig>,
+    pub(crate) fs_ops: Vec<config::FsOp>,
+    pub(crate) die_with_parent: bool,
+    pub(crate) network: config::NetworkConfig,
+    pub(crate) port_forwards: Vec<config::PortForward>,
+    pub(crate) hostname: Option<String>,
+    pub(crate) etc_files: Option<config::EtcFiles>,
+    pub(crate) overlay: Option<config::Overlay>,
+
+    pub(crate) sync: Option<ChildSync>,
+    /// Write end of the pipe the setup result is reported through.
+    pub(crate) report: Option<OwnedFd>,
+    /// Write end of the pipe the result of `result_callback` is sent through.
+    pub(crate) result: Option<OwnedFd>,
+    /// Parent side fds inherited by `clone(2)`, closed first in the child.
+    pub(crate) parent_fds: Vec<RawFd>,
+    /// Detached bind mounts, indexed like `mounts`.
+    pub(crate) mount_trees: Vec<Option<crate::mount::BindSource>>,
+    /// Staged contents of [`config::FsOp::File`], in order.
+    pub(crate) file_sources: VecDeque<crate::mount::BindSource>,
+    /// Index in `fs_ops` of the bind mount on `/` that replaced the
+    /// sandbox root, see [`Self::set_up_root_bind()`].
+    pub(crate) root_bind: Option<usize>,
 }
 
 impl WrapInner<'_> {
+    /// Whether the parent has to do some work after `clone(2)`.
+    pub(crate) fn needs_parent_setup(&self) -> bool {
+        matches!(self.network, config::NetworkConfig::Veth(_))
+            || !self.port_forwards.is_empty()
+            || !self.parent_callbacks.is_empty()
+    }
+
+    /// Prepare idmapped bind mounts in the parent.
+    ///
+    /// Setting an id mapping on a mount requires privileges over the
+    /// source filesystem, which the child does not have inside its
+    /// user namespace.
+    fn prepare_mount_trees(&mut self) -> Result<(), Error> {
+        self.mount_trees = self.mounts.iter().map(|_| None).collect();
+        for (i, mnt) in self.mounts.iter().enumerate() {
+            if !(mnt.is_bind() && mnt.is_idmapped()) {
+                continue;
+            }
+            let opts = crate::mount::MountOptions::parse(mnt);
+            let source = mnt.source.as_deref().unwrap_or(std::path::Path::new(""));
+            let userns = crate::mount::idmap_userns(mnt, &self.uid_maps, &self.gid_maps)?;
+            let fd = crate::mount::open_source(source)?;
+            let tree = crate::mount::clone_tree(&fd, opts.recursive)?;
+            crate::mount::set_idmap(&tree, &userns, opts.recursive)?;
+            self.mount_trees[i] = Some(crate::mount::BindSource::Tree(tree));
+        }
+        Ok(())
+    }
+
+    /// Open the sources of bind mounts, before the root dir is changed.
+    ///
+    /// Sources are never looked up by path again, so they cannot be
+    /// swapped for something else between this and [`Self::apply_fs_ops()`].
+    fn open_mount_trees(&mut self) -> Result<(), Error> {
+        use crate::mount::BindSource;
+        self.mount_trees.resize_with(self.mounts.len(), || None);
+        for (mnt, tree) in self.mounts.iter().zip(self.mount_trees.iter_mut()) {
+            if !mnt.is_bind() {
+                continue;
+            }
+            let opts = crate::mount::MountOptions::parse(mnt);
+            let source = mnt.source.as_deref().unwrap_or(std::path::Path::new(""));
+            let t = match tree.take() {
+                Some(t) => t,
+                None => BindSource::open(source, opts.recursive)
+                    .map_err(|e| e.at(&source.display().to_string()))?,
+            };
+            if let BindSource::Tree(t) = &t {
+                crate::mount::set_bind_flags(t, &opts)?;
+            }
+            *tree = Some(t);
+        }
+        Ok(())
+    }
+
+    /// Attach bind mounts, mount other filesystems and create files,
+    /// symlinks and directories, in order.
+    fn apply_fs_ops(&mut self) -> Result<(), Error> {
+        let done = self.root_bind.map_or(0, |n| n + 1);
+        for op in &self.fs_ops[done..] {
+            match op {
+                config::FsOp::Mount(i) => {
+                    let mnt = &self.mounts[*i];
+                    let opts = crate::mount::MountOptions::parse(mnt);
+                    let res = match &self.mount_trees[*i] {
+                        Some(source) => self.attach_bind(source, &mnt.destination, &opts),
+                        None => crate::mount::mount_config(mnt, &opts),
+                    };
+                    res.map_err(|e| e.at(&format!("mount {}", mnt.destination.display())))?;
+                }
+                config::FsOp::File { dest, .. } => {
+                    let source = self
+                        .file_sources
+                        .pop_front()
+                        .ok_or(Error::OsErrno(libc::EINVAL));
+                    let opts = crate::mount::MountOptions::new(rustix::mount::MountFlags::empty());
+                    source
+                        .and_then(|source| self.attach_bind(&source, dest, &opts))
+                        .map_err(|e| e.at(&format!("file {}", dest.display())))?
+                }
+                config::FsOp::Symlink { target, link } => Self::create_symlink(target, link)
+                    .map_err(|e| e.at(&format!("symlink {}", link.display())))?,
+                config::FsOp::Dir { path, mode } => Self::create_dir(path, *mode)
+                    .map_err(|e| e.at(&format!("dir {}", path.display())))?,
+                config::FsOp::RemountRo { path } => crate::mount::remount_readonly(path)
+                    .map_err(|e| e.at(&format!("remount {}", path.display())))?,
+            }
+        }
+        self.mount_trees.clear();
+        Ok(())
+    }
+
+    fn create_symlink(target: &std::path::Path, link: &std::path::Path) -> Result<(), Error> {
+        use std::fs::DirBuilder;
+        use std::os::unix::fs::DirBuilderExt;
+
+        if let Some(parent) = link.parent() {
+            DirBuilder::new()
+                .mode(0o755)
+                .recursive(true)
+                .create(parent)?;
+        }
+        match std::os::unix::fs::symlink(target, link) {
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                match std::fs::read_link(link) {
+                    Ok(existing) if existing == target => Ok(()),
+                    _ => Err(e.into()),
+                }
+            }
+            res => Ok(res?),
+        }
+    }
+
+    fn create_dir(path: &std::path::Path, mode: u32) -> Result<(), Error> {
+        use std::fs::{DirBuilder, Permissions};
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        DirBuilder::new().mode(0o755).recursive(true).create(path)?;
+        std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+        Ok(())
+    }
+
+    fn write_file(dest: &std::path::Path, data: &config::FileData, mode: u32) -> Result<(), Error> {
+        use std::fs::{DirBuilder, File, Permissions};
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        if let Some(parent) = dest.parent() {
+            DirBuilder::new()
+                .mode(0o755)
+                .recursive(true)
+                .create(parent)?;
+        }
+        let mut file = File::create(dest)?;
+        match data {
+            config::FileData::Bytes(bytes) => std::io::Write::write_all(&mut file, bytes)?,
+            config::FileData::Fd(fd) => {
+                // The offset is shared with the parent and earlier children.
+                match rustix::fs::seek(fd, rustix::fs::SeekFrom::Start(0)) {
+                    Ok(_) | Err(rustix::io::Errno::SPIPE) => (),
+                    Err(e) => return Err(e.into()),
+                }
+                let mut src = File::from(fd.try_clone()?);
+                std::io::copy(&mut src, &mut file)?;
+            }
+        }
+        // Not masked by the umask, unlike the mode of `open(2)`.
+        file.set_permissions(Permissions::from_mode(mode))?;
+        Ok(())
+    }
+
+    /// Attach an opened bind mount source at `dest`.
+    ///
+    /// Without `open_tree(2)`, it is bound with `mount(2)` through the
+    /// `/proc` of the old root kept by [`Self::set_up_tmpfs_cwd()`].
+    fn attach_bind(
+        &self,
+        source: &crate::mount::BindSource,
+        dest: &std::path::Path,
+        opts: &crate::mount::MountOptions,
+    ) -> Result<(), Error> {
+        let fd = match source {
+            crate::mount::BindSource::Tree(tree) => return crate::mount::attach_tree(tree, dest),
+            crate::mount::BindSource::Path(fd) => fd,
+        };
+        // The old root, with its `/proc`, might be detached.
+        let old_root = self.old_root().ok_or(Error::OsErrno(libc::ENOSYS))?;
+        let source = format!("{}/proc/self/fd/{}", old_root, fd.as_raw_fd());
+        crate::mount::legacy_bind(source.as_ref(), dest, opts)
+    }
+
+    /// Where the host root is reachable once the root dir is changed.
+    ///
+    /// Overlay and bind mounted roots detach it right away.
+    fn old_root(&self) -> Option<&'static str> {
+        if self.overlay.is_some() || self.root.is_some() || self.root_bind.is_some() {
+            None
+        } else if self.sandbox_mnt {
+            Some("/oldroot")
+        } else {
+            Some("")
+        }
+    }
+
+    /// Mount a minimal `/dev` with the nodes opened by
+    /// [`crate::mount::open_dev_nodes()`].
+    fn set_up_dev(&self, nodes: &[crate::mount::DevNode]) -> Result<(), Error> {
+        let dev = std::path::Path::new("/dev");
+        crate::mount::mount_dev_tmpfs(dev)?;
+        let opts = crate::mount::MountOptions::new(rustix::mount::MountFlags::empty());
+        for node in nodes {
+            if !crate::mount::mknod_dev_node(dev, node)? {
+                self.attach_bind(&node.source, &node.path(dev), &opts)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Listen on the host addresses of all port forwards.
+    ///
+    /// This is done before `clone(2)`, so that errors like
+    /// `EADDRINUSE` are reported without starting the child.
+    fn bind_forwards(&self) -> Result<Vec<(TcpListener, u16)>, Error> {
+        self.port_forwards
+            .iter()
+            .map(|f| {
+                TcpListener::bind(f.host_address)
+                    .map(|l| (l, f.container_port))
+                    .map_err(|e| Error::OsErrno(e.raw_os_error().unwrap_or(0)))
+            })
+            .collect()
+    }
+
     fn run_child(&mut self) -> isize {
-        self.apply_nsenter();
-        self.apply_unshare();
+        for fd in self.parent_fds.drain(..) {
+            unsafe { libc::close(fd) };
+        }
+        if self.die_with_parent {
+            unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+        }
+
+        let res = self.set_up_child();
+        let failed = res.is_err();
+        if let Some(report) = self.report.take() {
+            write_report(report, &res);
+        }
+        if failed {
+            return 127;
+        }
+
+        let ret = self.execute_callbacks();
+
+        if let (Some(cb), Some(fd)) = (self.result_callback.take(), self.result.take()) {
+            write_all(&fd, &cb());
+            return ret;
+        }
+
+        if !self.process.bin.is_empty() {
+            self.execute_process();
+        }
+
+        ret
+    }
+
+    /// Everything done in the child before callbacks and the program.
+    fn set_up_child(&mut self) -> Result<(), Error> {
+        self.apply_nsenter().map_err(|e| e.at("nsenter"))?;
+        self.apply_unshare().map_err(|e| e.at("unshare"))?;
 
         // Drop mmap and fd?
 
         if (self.uid_maps.len() + self.gid_maps.len()) > 0 {
-            self.set_id_map();
+            self.set_id_map().map_err(|e| e.at("id map"))?;
         }
 
-        if self.sandbox_mnt {
-            self.set_up_tmpfs_cwd();
+        if !matches!(self.namespace_unshare.pid, config::NamespaceItem::None)
+            || !matches!(self.namespace_nsenter.pid, config::NamespaceItem::None)
+        {
+            Self::enter_pid_ns().map_err(|e| e.at("pid namespace"))?;
         }
 
-        let ret = self.execute_callbacks();
+        if let Some(sync) = &self.sync {
+            sync.barrier().map_err(|e| e.at("parent setup"))?;
+        }
 
-        if !self.process.bin.is_empty() {
-            self.execute_process(); // exec ,no return
+        self.set_up_network().map_err(|e| e.at("network"))?;
+
+        if let Some(name) = &self.hostname {
+            util::sethostname(name).map_err(|e| e.at("hostname"))?;
+        }
+
+        // Generated before pivot_root, host files are needed to name users.
+        let etc_files = self
+            .etc_files
+            .as_ref()
+            .map(|cfg| self.generate_etc_files(cfg));
+
+        self.open_mount_trees()
+            .map_err(|e| e.at("bind mount source"))?;
+        let proc_tree = match &self.proc {
+            Some(proc) => match crate::mount::proc_tree(proc) {
+                Ok(tree) => Some(tree),
+                Err(e) if crate::mount::is_unsupported(&e) => None,
+                Err(e) => return Err(Self::proc_error(e)),
+            },
+            None => None,
+        };
+        let dev_nodes = match &self.dev {
+            Some(cfg) => Some(crate::mount::open_dev_nodes(cfg).map_err(|e| e.at("dev"))?),
+            None => None,
+        };
+        // Opened before the root is changed, there might be no /dev after.
+        let dev_null = match self.masked_paths.is_empty() {
+            true => None,
+            false => Some(
+                crate::mount::open_source("/dev/null".as_ref()).map_err(|e| e.at("/dev/null"))?,
+            ),
+        };
+
+        let files: Vec<_> = self
+            .fs_ops
+            .iter()
+            .filter_map(|op| match op {
+                config::FsOp::File { data, mode, .. } => Some((data.clone(), *mode)),
+                _ => None,
+            })
+            .collect();
+        if !files.is_empty() {
+            self.file_sources = self.stage_files(files).map_err(|e| e.at("files"))?.into();
+        }
+        let etc_files = match etc_files {
+            Some(files) => {
+                let data = files
+                    .iter()
+                    .map(|(_, content)| (config::FileData::Bytes(content.as_bytes().into()), 0o644))
+                    .collect();
+                let sources = self.stage_files(data).map_err(|e| e.at("etc files"))?;
+                Some(files.into_iter().map(|(name, _)| name).zip(sources))
+            }
+            None => None,
+        };
+
+        for cb in self.setup_callbacks.drain(..) {
+            cb().map_err(|e| e.at("setup callback"))?;
+        }
+
+        if let Some(overlay) = &self.overlay {
+            self.set_up_overlay_root(overlay)?;
+        } else if let Some(root) = &self.root {
+            self.set_up_bind_root(root).map_err(|e| e.at("root"))?;
+        } else if self.sandbox_mnt {
+            self.set_up_tmpfs_cwd().map_err(|e| e.at("sandbox root"))?;
+            self.set_up_root_bind().map_err(|e| e.at("root bind"))?;
+        }
+
+        if let Some(proc) = &self.proc {
+            match &proc_tree {
+                Some(tree) => crate::mount::attach_tree(tree, &proc.destination),
+                None => crate::mount::legacy_mount_proc(proc),
+            }
+            .map_err(Self::proc_error)?;
+        }
+
+        if let Some(nodes) = &dev_nodes {
+            self.set_up_dev(nodes).map_err(|e| e.at("dev"))?;
+        }
+
+        self.apply_fs_ops()?;
+
+        if let Some(dev_null) = &dev_null {
+            for path in &self.masked_paths {
+                crate::mount::mask_path(path, dev_null)
+                    .map_err(|e| e.at(&format!("mask {}", path.display())))?;
+            }
+        }
+        for path in &self.readonly_paths {
+            crate::mount::readonly_path(path)
+                .map_err(|e| e.at(&format!("readonly {}", path.display())))?;
+        }
+
+        if let Some(files) = etc_files {
+            self.install_etc_files(files)
+                .map_err(|e| e.at("etc files"))?;
+        }
+
+        if self.old_root() == Some("/oldroot") {
+            Self::drop_old_root().map_err(|e| e.at("old root"))?;
+        }
+        if let Some(root) = &self.root {
+            if root.readonly == Some(true) {
+                crate::mount::remount_readonly("/".as_ref()).map_err(|e| e.at("root"))?;
+            }
+        }
+        if self.overlay.is_some() || self.root.is_some() || self.sandbox_mnt {
+            self.finish_propagation()
+                .map_err(|e| e.at("mount propagation"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Fork into the PID namespace set up by [`Self::apply_unshare()`]
+    /// or [`Self::apply_nsenter()`], which only applies to new children.
+    ///
+    /// The rest of the setup, the callbacks and the program run in the
+    /// new process. This one waits for it and exits with its status.
+    fn enter_pid_ns() -> Result<(), Error> {
+        use nix::unistd::{fork, ForkResult};
+
+        match unsafe { fork() }.map_err(|e| Error::OsErrno(e as i32))? {
+            ForkResult::Child => {
+                // Do not outlive the process the parent knows about.
+                unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+                Ok(())
+            }
+            ForkResult::Parent { child } => {
+                // Signals sent to this process are meant for the child,
+                // like those of an OCI runtime's `kill`.
+                CHILD_PID.store(child.as_raw(), Ordering::Relaxed);
+                for sig in 1..libc::SIGRTMIN() {
+                    if sig != libc::SIGKILL && sig != libc::SIGSTOP && sig != libc::SIGCHLD {
+                        unsafe {
+                            libc::signal(sig, forward_signal as *const () as libc::sighandler_t)
+                        };
+                    }
+                }
+                Self::wait_pid_ns(child)
+            }
         }
+    }
+
+    /// Wait for the first process of the PID namespace and exit like it.
+    fn wait_pid_ns(child: nix::unistd::Pid) -> ! {
+        use nix::sys::wait::{waitpid, WaitStatus};
 
-        return ret;
+        loop {
+            match waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, code)) => unsafe { libc::_exit(code) },
+                Ok(WaitStatus::Signaled(_, sig, _)) => unsafe {
+                    libc::signal(sig as i32, libc::SIG_DFL);
+                    libc::kill(libc::getpid(), sig as i32);
+                    libc::_exit(128 + sig as i32)
+                },
+                Ok(_) | Err(nix::errno::Errno::EINTR) => continue,
+                Err(_) => unsafe { libc::_exit(127) },
+            }
+        }
     }
 
-    pub(crate) fn execute_process(&mut self) {
+    fn proc_error(e: Error) -> Error {
+        match e {
+            Error::OsErrno(libc::EPERM) | Error::SetupFailed(_, libc::EPERM) => e
+                .at("proc (needs a PID namespace owned by the user namespace, \
+                 and a procfs without mounts on top of it)"),
+            e => e.at("proc"),
+        }
+    }
+
+    /// Execute the program, or write why it cannot be executed to
+    /// stderr, like `bwrap` does, and exit with `1`.
+    pub(crate) fn execute_process(&mut self) -> ! {
         use std::os::unix::process::CommandExt;
         use std::process::Command;
 
         let mut cmd = Command::new(self.process.bin());
         cmd.args(self.process.args());
 
-        match &self.process.cwd {
-            Some(cwd) => {cmd.current_dir(cwd);},
-            None => (),
+        if let Some(cwd) = &self.process.cwd {
+            cmd.current_dir(cwd);
         }
 
         if self.process.env_no_inheriting {
-            //cmd.env_clear();
+            cmd.env_clear();
+        }
+
+        if let Some(user) = &self.process.user {
+            cmd.uid(user.uid()).gid(user.gid());
         }
 
         // Set up envvar
@@ -109,43 +785,53 @@ impl WrapInner<'_> {
             }
         }
 
-        cmd.exec();
+        let e = cmd.exec();
+        let msg = format!(
+            "nswrap: execvp {}: {}\n",
+            self.process.bin.to_string_lossy(),
+            e
+        );
+        unsafe {
+            // Not through the lock of `std::io::Stderr`, which another
+            // thread of the parent might have held at `clone(2)`.
+            libc::write(libc::STDERR_FILENO, msg.as_ptr().cast(), msg.len());
+            libc::_exit(1)
+        }
     }
 
-    pub(crate) fn apply_nsenter(&mut self) {
-        Self::apply_namespace_item(self.namespace_nsenter.user, CloneFlags::NEWUSER);
-        Self::apply_namespace_item(self.namespace_nsenter.mount, CloneFlags::NEWNS);
-        Self::apply_namespace_item(self.namespace_nsenter.cgroup, CloneFlags::NEWCGROUP);
-        Self::apply_namespace_item(self.namespace_nsenter.uts, CloneFlags::NEWUTS);
-        Self::apply_namespace_item(self.namespace_nsenter.ipc, CloneFlags::NEWIPC);
-        Self::apply_namespace_item(self.namespace_nsenter.pid, CloneFlags::NEWPID);
-        Self::apply_namespace_item(self.namespace_nsenter.network, CloneFlags::NEWNET);
+    pub(crate) fn apply_nsenter(&mut self) -> Result<(), Error> {
+        Self::apply_namespace_item(self.namespace_nsenter.user, CloneFlags::NEWUSER)?;
+        Self::apply_namespace_item(self.namespace_nsenter.mount, CloneFlags::NEWNS)?;
+        Self::apply_namespace_item(self.namespace_nsenter.cgroup, CloneFlags::NEWCGROUP)?;
+        Self::apply_namespace_item(self.namespace_nsenter.uts, CloneFlags::NEWUTS)?;
+        Self::apply_namespace_item(self.namespace_nsenter.ipc, CloneFlags::NEWIPC)?;
+        Self::apply_namespace_item(self.namespace_nsenter.pid, CloneFlags::NEWPID)?;
+        Self::apply_namespace_item(self.namespace_nsenter.network, CloneFlags::NEWNET)
     }
 
-    pub(crate) fn apply_unshare(&mut self) {
-        Self::apply_namespace_item(self.namespace_unshare.user, CloneFlags::NEWUSER);
-        Self::apply_namespace_item(self.namespace_unshare.mount, CloneFlags::NEWNS);
-        Self::apply_namespace_item(self.namespace_unshare.cgroup, CloneFlags::NEWCGROUP);
-        Self::apply_namespace_item(self.namespace_unshare.uts, CloneFlags::NEWUTS);
-        Self::apply_namespace_item(self.namespace_unshare.ipc, CloneFlags::NEWIPC);
-        Self::apply_namespace_item(self.namespace_unshare.pid, CloneFlags::NEWPID);
-        Self::apply_namespace_item(self.namespace_unshare.network, CloneFlags::NEWNET);
+    pub(crate) fn apply_unshare(&mut self) -> Result<(), Error> {
+        Self::apply_namespace_item(self.namespace_unshare.user, CloneFlags::NEWUSER)?;
+        Self::apply_namespace_item(self.namespace_unshare.mount, CloneFlags::NEWNS)?;
+        Self::apply_namespace_item(self.namespace_unshare.cgroup, CloneFlags::NEWCGROUP)?;
+        Self::apply_namespace_item(self.namespace_unshare.uts, CloneFlags::NEWUTS)?;
+        Self::apply_namespace_item(self.namespace_unshare.ipc, CloneFlags::NEWIPC)?;
+        Self::apply_namespace_item(self.namespace_unshare.pid, CloneFlags::NEWPID)?;
+        Self::apply_namespace_item(self.namespace_unshare.network, CloneFlags::NEWNET)
     }
 
-    fn apply_namespace_item(ns: config::NamespaceItem, flag: CloneFlags) {
+    fn apply_namespace_item(ns: config::NamespaceItem, flag: CloneFlags) -> Result<(), Error> {
         match ns {
-            config::NamespaceItem::None => (),
-            config::NamespaceItem::Unshare => {
-                crate::util::unshare(flag).unwrap();
-            }
-            config::NamespaceItem::Enter(fd) => {
-                crate::util::setns(fd, flag).unwrap();
-            }
+            config::NamespaceItem::None => Ok(()),
+            config::NamespaceItem::Unshare => crate::util::unshare(flag),
+            config::NamespaceItem::Enter(fd) => crate::util::setns(fd, flag),
         }
     }
 
-    pub(crate) fn write_id_map<S: AsRef<OsStr>>(file: S, map: &Vec<config::IdMap>) {
-        let file = OpenOptions::new().write(true).open(file.as_ref()).unwrap();
+    pub(crate) fn write_id_map<S: AsRef<OsStr>>(
+        file: S,
+        map: &Vec<config::IdMap>,
+    ) -> Result<(), Error> {
+        let file = OpenOptions::new().write(true).open(file.as_ref())?;
         let mut content = OsString::new();
         for i in map {
             content.push(format!("{}", i.container_id()));
@@ -154,22 +840,142 @@ impl WrapInner<'_> {
             content.push(" ");
             content.push(format!("{}\n", i.size()));
         }
-        rustix::io::write(file, content.as_bytes()).unwrap();
+        rustix::io::write(file, content.as_bytes())?;
+        Ok(())
     }
 
-    pub(crate) fn set_id_map(&self) {
+    pub(crate) fn set_id_map(&self) -> Result<(), Error> {
         let pid = util::get_pid();
-        Self::write_id_map(format!("/proc/{}/uid_map", pid), &self.uid_maps);
+        Self::write_id_map(format!("/proc/{}/uid_map", pid), &self.uid_maps)?;
 
         // Write /proc/pid/setgroups before wite /proc/pid/gid_map, or it will fail.
         // See https://manpages.opensuse.org/Tumbleweed/man-pages/user_namespaces.7.en.html
         let file = OpenOptions::new()
             .write(true)
-            .open(format!("/proc/{}/setgroups", pid))
-            .unwrap();
-        rustix::io::write(file, b"deny").unwrap();
+            .open(format!("/proc/{}/setgroups", pid))?;
+        rustix::io::write(file, b"deny")?;
+
+        Self::write_id_map(format!("/proc/{}/gid_map", pid), &self.gid_maps)
+    }
+
+    pub(crate) fn set_up_network(&self) -> Result<(), Error> {
+        match self.network {
+            config::NetworkConfig::None => Ok(()),
+            config::NetworkConfig::LoopbackOnly => crate::net::loopback_up(),
+            config::NetworkConfig::Veth(ref veth) => crate::net::veth_container_setup(veth),
+        }
+    }
+
+    /// Render the content of generated files in `/etc`.
+    pub(crate) fn generate_etc_files(&self, cfg: &config::EtcFiles) -> Vec<(&'static str, String)> {
+        let host_passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+        let host_group = std::fs::read_to_string("/etc/group").unwrap_or_default();
+
+        let mapped = |maps: &[config::IdMap], id: u32| {
+            maps.iter()
+                .any(|m| id >= m.container_id && id - m.container_id < m.size)
+        };
+        let user = self.process.user.as_ref();
+        // The primary group of users without a group of the same id.
+        let default_gid = match user {
+            Some(user) => user.gid,
+            None => self.gid_maps.first().map_or(65534, |m| m.container_id),
+        };
+
+        let uids = needed_ids(
+            &self.uid_maps,
+            &[0, user.map_or(0, |u| u.uid)],
+            util::get_uid(),
+        );
+        let mut passwd = String::new();
+        let mut gids = vec![0, default_gid];
+        for &(id, host_id) in &uids {
+            let name = etc_name(id, host_id, util::get_uid(), &host_passwd);
+            let home = if id == 0 { "/root" } else { "/" };
+            let gid = match user {
+                Some(user) if user.uid == id => user.gid,
+                _ if mapped(&self.gid_maps, id) => id,
+                _ => default_gid,
+            };
+            gids.push(gid);
+            passwd.push_str(&format!("{name}:x:{id}:{gid}:{name}:{home}:/bin/sh\n"));
+        }
+        passwd.push_str("nobody:x:65534:65534:nobody:/:/sbin/nologin\n");
+
+        let mut group = String::new();
+        for (id, host_id) in needed_ids(&self.gid_maps, &gids, util::get_gid()) {
+            let name = etc_name(id, host_id, util::get_gid(), &host_group);
+            group.push_str(&format!("{name}:x:{id}:\n"));
+        }
+        group.push_str("nogroup:x:65534:\n");
+
+        let mut hosts = String::from("127.0.0.1\tlocalhost\n::1\tlocalhost\n");
+        if let Some(name) = &self.hostname {
+            hosts.push_str(&format!("127.0.1.1\t{name}\n"));
+        }
+
+        let mut resolv = String::new();
+        for ns in &cfg.nameservers {
+            resolv.push_str(&format!("nameserver {ns}\n"));
+        }
+        if !cfg.search.is_empty() {
+            resolv.push_str(&format!("search {}\n", cfg.search.join(" ")));
+        }
+
+        vec![
+            ("passwd", passwd),
+            ("group", group),
+            ("hosts", hosts),
+            ("resolv.conf", resolv),
+        ]
+    }
+
+    /// Bind files staged by [`Self::stage_files()`] read-only over
+    /// `/etc/<name>`.
+    fn install_etc_files(
+        &self,
+        files: impl Iterator<Item = (&'static str, crate::mount::BindSource)>,
+    ) -> Result<(), Error> {
+        use rustix::mount::MountFlags;
+
+        let opts = crate::mount::MountOptions::new(MountFlags::RDONLY);
+        for (name, source) in files {
+            if let crate::mount::BindSource::Tree(tree) = &source {
+                crate::mount::set_readonly(tree, false)?;
+            }
+            self.attach_bind(&source, format!("/etc/{}", name).as_ref(), &opts)?;
+        }
+        Ok(())
+    }
+
+    /// Write `files` with their modes to a private tmpfs on the staging
+    /// dir, and open them as bind mount sources, in order.
+    ///
+    /// Nothing is written to the filesystems the files are bound over
+    /// later. The tmpfs is detached once the files are cloned, kernels
+    /// without `open_tree(2)` keep it mounted to bind them by path.
+    fn stage_files(
+        &self,
+        files: Vec<(config::FileData, u32)>,
+    ) -> Result<Vec<crate::mount::BindSource>, Error> {
+        use crate::mount::BindSource;
+        use rustix::mount::{unmount, MountFlags, UnmountFlags};
 
-        Self::write_id_map(format!("/proc/{}/gid_map", pid), &self.uid_maps);
+        let staging = &self.sandbox.staging;
+        self.isolate_mounts()?;
+        let mut opts = crate::mount::MountOptions::new(MountFlags::NODEV | MountFlags::NOSUID);
+        opts.data.push("mode=700".into());
+        crate::mount::mount_fs("tmpfs", Some("tmpfs".as_ref()), staging, &opts)?;
+        let mut sources = Vec::new();
+        for (i, (data, mode)) in files.into_iter().enumerate() {
+            let path = staging.join(i.to_string());
+            Self::write_file(&path, &data, mode)?;
+            sources.push(BindSource::open(&path, false)?);
+        }
+        if sources.iter().all(|s| matches!(s, BindSource::Tree(_))) {
+            unmount(staging, UnmountFlags::DETACH)?;
+        }
+        Ok(sources)
     }
 
     pub(crate) fn execute_callbacks(&mut self) -> isize {
@@ -177,62 +983,319 @@ impl WrapInner<'_> {
         for _i in 0..self.callbacks.len() {
             ret = self.callbacks.pop_front().unwrap()();
         }
-        return ret;
+        ret
+    }
+
+    /// Mount the tmpfs configured by [`config::SandboxMnt`] on its
+    /// staging dir.
+    ///
+    /// All mounts are made slave (or private) first, so that neither this
+    /// nor `pivot_root(2)` affect the host.
+    fn mount_staging(&self) -> Result<&std::path::Path, Error> {
+        use rustix::mount::MountFlags;
+
+        let cfg = &self.sandbox;
+        self.isolate_mounts()?;
+
+        let mut opts = crate::mount::MountOptions::new(MountFlags::NODEV | MountFlags::NOSUID);
+        if let Some(size) = cfg.size {
+            opts.data.push(format!("size={}", size));
+        }
+        if let Some(mode) = cfg.mode {
+            opts.data.push(format!("mode={:o}", mode));
+        }
+        if let Some(nr_inodes) = cfg.nr_inodes {
+            opts.data.push(format!("nr_inodes={}", nr_inodes));
+        }
+        crate::mount::mount_fs("tmpfs", Some("tmpfs".as_ref()), &cfg.staging, &opts)?;
+        Ok(&cfg.staging)
+    }
+
+    /// Make all mounts slave (or private), so that changing the root dir
+    /// does not affect the host.
+    fn isolate_mounts(&self) -> Result<(), Error> {
+        use rustix::mount::{mount_change, MountPropagationFlags};
+        let propagation = match self.sandbox.propagation {
+            config::Propagation::Private => MountPropagationFlags::PRIVATE,
+            config::Propagation::Slave | config::Propagation::Shared => {
+                MountPropagationFlags::SLAVE
+            }
+        };
+        mount_change("/", propagation | MountPropagationFlags::REC)?;
+        Ok(())
+    }
+
+    /// Make mounts shared again once the root dir is set up, if configured.
+    ///
+    /// They form new peer groups, which are not connected to the host.
+    fn finish_propagation(&self) -> Result<(), Error> {
+        use rustix::mount::{mount_change, MountPropagationFlags};
+        if self.sandbox.propagation == config::Propagation::Shared {
+            mount_change(
+                "/",
+                MountPropagationFlags::SHARED | MountPropagationFlags::REC,
+            )?;
+        }
+        Ok(())
     }
 
     /**
     Create tmpfs as root, simulate brwrap's behaviour.
 
+    The host root is kept at `/oldroot` until [`Self::drop_old_root()`],
+    so that bind mounts can still fall back to its paths.
+
     Due to kernel bug#183461 ,this can only be called after setup uid
     and gid mapping.
     */
-    pub(crate) fn set_up_tmpfs_cwd(&self) {
+    pub(crate) fn set_up_tmpfs_cwd(&self) -> Result<(), Error> {
         use nix::unistd::pivot_root;
-        use rustix::fs::change_mount;
-        use rustix::fs::mount;
-        use rustix::fs::MountFlags;
-        use rustix::fs::MountPropagationFlags;
         use std::env::set_current_dir;
         use std::fs::DirBuilder;
         use std::os::unix::fs::DirBuilderExt;
 
-        let tmp_path = "/tmp";
-        //
-        change_mount(
-            "/",
-            MountPropagationFlags::SLAVE | MountPropagationFlags::REC,
-            // TODO: Fix MountPropagationFlags::SILENT
-        )
-        .unwrap();
-
-        mount(
-            "tmpfs",
-            tmp_path,
-            "tmpfs",
-            MountFlags::NODEV | MountFlags::NOSUID,
-            "",
-        )
-        .unwrap();
-
-        set_current_dir(tmp_path).unwrap();
+        let staging = self.mount_staging()?;
+        set_current_dir(staging)?;
+        DirBuilder::new().mode(0o755).create("oldroot")?;
+        pivot_root(".", "oldroot").map_err(|e| Error::OsErrno(e as i32))?;
+        set_current_dir("/")?;
+        Ok(())
+    }
+
+    /**
+    Bind mount a host directory as root and pivot into it, like OCI
+    runtimes do with the root of a bundle.
+
+    A read-only root is only made read-only at the end of the setup,
+    so that mount points can still be created in it.
+    */
+    pub(crate) fn set_up_bind_root(&self, root: &config::Root) -> Result<(), Error> {
+        use nix::unistd::pivot_root;
+        use rustix::mount::{unmount, MountFlags, UnmountFlags};
+        use std::env::set_current_dir;
+
+        self.isolate_mounts()?;
+        let mut opts = crate::mount::MountOptions::new(MountFlags::empty());
+        opts.recursive = true;
+        // pivot_root(2) needs a mount point, so bind the dir over itself.
+        match crate::mount::BindSource::open(&root.path, true)? {
+            crate::mount::BindSource::Tree(tree) => crate::mount::attach_tree(&tree, &root.path)?,
+            crate::mount::BindSource::Path(_) => {
+                crate::mount::legacy_bind(&root.path, &root.path, &opts)?
+            }
+        }
+        set_current_dir(&root.path)?;
+        pivot_root(".", ".").map_err(|e| Error::OsErrno(e as i32))?;
+        unmount(".", UnmountFlags::DETACH)?;
+        set_current_dir("/")?;
+        Ok(())
+    }
+
+    /**
+    Make the last bind mount on `/` of the fs ops the root dir, in place
+    of the tmpfs of [`Self::set_up_tmpfs_cwd()`], like `bwrap --bind / /`.
+
+    This is done before `/proc`, `/dev` and the other fs ops are set up,
+    so that they end up on top of it. The fs ops before it would be
+    hidden under it, they are skipped. The tmpfs is detached together
+    with the host root below it.
+    */
+    fn set_up_root_bind(&mut self) -> Result<(), Error> {
+        use nix::unistd::pivot_root;
+        use rustix::mount::{unmount, UnmountFlags};
+        use std::env::set_current_dir;
+
+        let found = self
+            .fs_ops
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(n, op)| match op {
+                config::FsOp::Mount(i)
+                    if self.mounts[*i].destination == std::path::Path::new("/")
+                        && self.mount_trees[*i].is_some() =>
+                {
+                    Some((n, *i))
+                }
+                _ => None,
+            });
+        let Some((n, i)) = found else {
+            return Ok(());
+        };
+        let opts = crate::mount::MountOptions::parse(&self.mounts[i]);
+        let newroot = std::path::Path::new("/newroot");
+        std::fs::create_dir(newroot)?;
+        if let Some(source) = &self.mount_trees[i] {
+            self.attach_bind(source, newroot, &opts)?;
+        }
+        set_current_dir(newroot)?;
+        pivot_root(".", ".").map_err(|e| Error::OsErrno(e as i32))?;
+        unmount(".", UnmountFlags::DETACH)?;
+        set_current_dir("/")?;
+
+        let skipped = self.fs_ops[..n]
+            .iter()
+            .filter(|op| matches!(op, config::FsOp::File { .. }))
+            .count();
+        self.file_sources.drain(..skipped);
+        self.root_bind = Some(n);
+        Ok(())
+    }
+
+    /// Detach and remove the host root left by [`Self::set_up_tmpfs_cwd()`].
+    pub(crate) fn drop_old_root() -> Result<(), Error> {
+        use rustix::mount::{unmount, UnmountFlags};
+        unmount("/oldroot", UnmountFlags::DETACH)?;
+        std::fs::remove_dir("/oldroot")?;
+        Ok(())
+    }
+
+    /**
+    Mount an overlayfs as root and pivot into it.
+
+    Lower layers and a persistent upper layer are opened before the
+    staging tmpfs is mounted, and passed to overlayfs as `/proc/self/fd`
+    paths, so they stay reachable even if they live below the staging
+    directory.
+    */
+    pub(crate) fn set_up_overlay_root(&self, overlay: &config::Overlay) -> Result<(), Error> {
+        use nix::unistd::pivot_root;
+        use rustix::fs::{open, Mode, OFlags};
+        use rustix::mount::{unmount, MountFlags, UnmountFlags};
+        use std::env::set_current_dir;
+        use std::fs::DirBuilder;
+        use std::os::unix::fs::DirBuilderExt;
 
         let mut dir = DirBuilder::new();
-        dir.mode(0o755);
-        dir.create("/tmp/newroot").unwrap();
-        dir.create("oldroot").unwrap();
-        mount(
-            "newroot",
-            "newroot",
-            "",
-            MountFlags::SILENT | MountFlags::BIND | MountFlags::REC,
-            "",
-        )
-        .unwrap();
+        dir.mode(0o755).recursive(true);
+
+        let open_dir = |p: &std::path::Path| -> Result<OwnedFd, Error> {
+            Ok(open(
+                p,
+                OFlags::PATH | OFlags::DIRECTORY | OFlags::CLOEXEC,
+                Mode::empty(),
+            )?)
+        };
+        let fd_path = |fd: &OwnedFd| format!("/proc/self/fd/{}", fd.as_raw_fd());
+
+        let lower = overlay
+            .lower
+            .iter()
+            .map(|p| open_dir(p))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.at("overlay lower dir"))?;
+        let persistent = match &overlay.upper {
+            Some(state) => {
+                dir.create(state.join("upper"))?;
+                dir.create(state.join("work"))?;
+                Some((
+                    open_dir(&state.join("upper"))?,
+                    open_dir(&state.join("work"))?,
+                ))
+            }
+            None => None,
+        };
+
+        let step = |e: Error| e.at("overlay staging");
+        let staging = self.mount_staging().map_err(step)?;
+        let newroot = staging.join("newroot");
+        dir.create(&newroot).map_err(|e| step(e.into()))?;
 
-        pivot_root(tmp_path, "oldroot").unwrap(); // todo: Clean this!
+        let (upper, work) = match &persistent {
+            Some((upper, work)) => (fd_path(upper), fd_path(work)),
+            None => {
+                let (upper, work) = (staging.join("upper"), staging.join("work"));
+                dir.create(&upper).map_err(|e| step(e.into()))?;
+                dir.create(&work).map_err(|e| step(e.into()))?;
+                (upper.display().to_string(), work.display().to_string())
+            }
+        };
+        let mut opts = crate::mount::MountOptions::new(MountFlags::empty());
+        opts.data = vec![
+            format!(
+                "lowerdir={}",
+                lower.iter().map(fd_path).collect::<Vec<_>>().join(":")
+            ),
+            format!("upperdir={}", upper),
+            format!("workdir={}", work),
+        ];
+        let user_ns = !matches!(self.namespace_unshare.user, config::NamespaceItem::None);
+        if user_ns {
+            opts.data.push("userxattr".into());
+        }
+        if let Err(e) = crate::mount::mount_fs("overlay", Some("overlay".as_ref()), &newroot, &opts)
+        {
+            let errno = match e {
+                Error::OsErrno(errno) | Error::SetupFailed(_, errno) => errno,
+                _ => 0,
+            };
+            let step = match errno {
+                libc::EPERM | libc::EINVAL if user_ns => {
+                    "overlay root (rootless overlayfs needs Linux 5.11 or newer)"
+                }
+                _ => "overlay root",
+            };
+            return Err(e.at(step));
+        }
+
+        let step = |e: Error| e.at("overlay pivot_root");
+        set_current_dir(&newroot).map_err(|e| step(e.into()))?;
+        pivot_root(".", ".").map_err(|e| step(Error::OsErrno(e as i32)))?;
+        unmount(".", UnmountFlags::DETACH).map_err(|e| step(e.into()))?;
+        set_current_dir("/").map_err(|e| step(e.into()))?;
+        Ok(())
     }
 }
 
+/// Container and host ids of the mapped ones among `wanted` and the id
+/// that `current` on the host maps to, sorted.
+///
+/// Maps can cover whole ranges of subordinate ids, so they are not
+/// expanded. The overflow id is left out, it has its own entry.
+fn needed_ids(maps: &[config::IdMap], wanted: &[u32], current: u32) -> Vec<(u32, u32)> {
+    let to_host = |id: u32| {
+        maps.iter()
+            .find(|m| id >= m.container_id && id - m.container_id < m.size)
+            .map(|m| m.host_id + (id - m.container_id))
+    };
+    let from_current = maps
+        .iter()
+        .find(|m| current >= m.host_id && current - m.host_id < m.size)
+        .map(|m| m.container_id + (current - m.host_id));
+    let mut ids: Vec<(u32, u32)> = Vec::new();
+    for id in wanted.iter().copied().chain(from_current) {
+        if id == 65534 || ids.iter().any(|(i, _)| *i == id) {
+            continue;
+        }
+        if let Some(host_id) = to_host(id) {
+            ids.push((id, host_id));
+        }
+    }
+    ids.sort_unstable();
+    ids
+}
+
+/// Name of a mapped id in generated `/etc/passwd` or `/etc/group`.
+///
+/// `0` is always root, the current user keeps its name from the host
+/// database, others are named after their id.
+fn etc_name(id: u32, host_id: u32, current: u32, host_db: &str) -> String {
+    if id == 0 {
+        return "root".into();
+    }
+    if host_id == current {
+        let found = host_db.lines().find_map(|l| {
+            let mut fields = l.split(':');
+            let name = fields.next()?;
+            (fields.nth(1)?.parse::<u32>().ok()? == current).then(|| name.to_string())
+        });
+        if let Some(name) = found {
+            return name;
+        }
+    }
+    format!("u{}", id)
+}
+
 #[cfg(test)]
 mod test {
 
@@ -240,4 +1303,26 @@ mod test {
     fn test() {
         crate::Wrap::new_program("/bin/sh");
     }
+
+    #[test]
+    fn needed_ids_of_large_maps() {
+        let maps = [
+            crate::config::IdMap {
+                host_id: 1000,
+                container_id: 0,
+                size: 1,
+            },
+            crate::config::IdMap {
+                host_id: 100000,
+                container_id: 1,
+                size: u32::MAX - 1,
+            },
+        ];
+        assert_eq!(
+            super::needed_ids(&maps, &[0, 1000, 65534, 0], 1000),
+            [(0, 1000), (1000, 100999)]
+        );
+        assert_eq!(super::needed_ids(&maps, &[5], 7), [(5, 100004)]);
+        assert!(super::needed_ids(&[], &[0], 0).is_empty());
+    }
 }
@@ -0,0 +1,497 @@
+//! Render a [`Wrap`] as a `bwrap` command line or as a list of steps.
+use crate::mount::MountOptions;
+use crate::{config, util, Wrap};
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Marks what `bwrap` cannot do in [`Wrap::to_bwrap_args()`].
+///
+/// It is followed by a description, bwrap rejects it as an unknown
+/// option.
+pub const BWRAP_UNSUPPORTED: &str = "--nswrap-unsupported";
+
+/// Names of the namespaces of a set that are not [`config::NamespaceItem::None`].
+fn namespaces(set: &config::NamespaceSet) -> Vec<(&'static str, config::NamespaceItem)> {
+    [
+        ("user", set.user),
+        ("mount", set.mount),
+        ("cgroup", set.cgroup),
+        ("uts", set.uts),
+        ("ipc", set.ipc),
+        ("pid", set.pid),
+        ("network", set.network),
+        ("time", set.time),
+    ]
+    .into_iter()
+    .filter(|(_, ns)| !matches!(ns, config::NamespaceItem::None))
+    .collect()
+}
+
+fn id_maps(maps: &[config::IdMap]) -> String {
+    maps.iter()
+        .map(|m| format!("{}->{} ({})", m.host_id, m.container_id, m.size))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Args of bwrap collected with markers for what it cannot do.
+#[derive(Default)]
+struct BwrapArgs(Vec<OsString>);
+
+impl BwrapArgs {
+    fn push<I: IntoIterator<Item = S>, S: Into<OsString>>(&mut self, args: I) {
+        self.0.extend(args.into_iter().map(Into::into));
+    }
+
+    fn path(&mut self, opt: &str, path: &Path) {
+        self.push([OsString::from(opt), path.into()]);
+    }
+
+    fn unsupported(&mut self, what: String) {
+        self.push([BWRAP_UNSUPPORTED.into(), what]);
+    }
+}
+
+impl Wrap<'_> {
+    /// Render this `Wrap` as the arguments of an equivalent `bwrap`
+    /// command, without `bwrap` itself.
+    ///
+    /// Anything bwrap cannot do, like callbacks, is rendered as
+    /// [`BWRAP_UNSUPPORTED`] followed by a description.
+    pub fn to_bwrap_args(&self) -> Vec<OsString> {
+        let mut args = BwrapArgs::default();
+
+        for (name, _) in namespaces(&self.namespace_unshare) {
+            match name {
+                "mount" => (),
+                "network" => args.push(["--unshare-net"]),
+                "time" => args.unsupported("time namespace".into()),
+                name => args.push([format!("--unshare-{}", name)]),
+            }
+        }
+        for (name, ns) in namespaces(&self.namespace_nsenter) {
+            let fd = match ns {
+                config::NamespaceItem::Enter(fd) => fd,
+                _ => continue,
+            };
+            match name {
+                "user" | "pid" => args.push([format!("--{}ns", name), fd.to_string()]),
+                name => args.unsupported(format!("enter {} namespace", name)),
+            }
+        }
+        if matches!(self.namespace_unshare.mount, config::NamespaceItem::None) {
+            args.unsupported("no new mount namespace".into());
+        }
+        for (opt, maps, own) in [
+            ("--uid", &self.uid_maps, util::get_uid()),
+            ("--gid", &self.gid_maps, util::get_gid()),
+        ] {
+            match maps.as_slice() {
+                [] => (),
+                [m] if m.host_id == own && m.size == 1 => {
+                    if m.container_id != own {
+                        args.push([opt.to_string(), m.container_id.to_string()]);
+                    }
+                }
+                maps => args.unsupported(format!("{} map {}", &opt[2..], id_maps(maps))),
+            }
+        }
+
+        if self.overlay.is_some() {
+            args.unsupported("overlay root".into());
+        } else if let Some(root) = &self.root {
+            match root.readonly == Some(true) {
+                true => args.push([
+                    OsString::from("--ro-bind"),
+                    root.path.clone().into(),
+                    "/".into(),
+                ]),
+                false => args.push([
+                    OsString::from("--bind"),
+                    root.path.clone().into(),
+                    "/".into(),
+                ]),
+            }
+        } else if !self.sandbox_mnt {
+            args.push(["--bind", "/", "/"]);
+        }
+        let sandbox = &self.sandbox;
+        if sandbox.size.is_some() || sandbox.mode.is_some() || sandbox.nr_inodes.is_some() {
+            args.unsupported("options of the root tmpfs".into());
+        }
+        if sandbox.propagation != config::Propagation::Slave {
+            args.unsupported(format!("{:?} mount propagation", sandbox.propagation));
+        }
+
+        if let Some(name) = &self.hostname {
+            args.push(["--hostname", name]);
+        }
+        if let config::NetworkConfig::Veth(_) = &self.network {
+            args.unsupported("veth network".into());
+        }
+        for forward in &self.port_forwards {
+            args.unsupported(format!(
+                "port forward {} -> {}",
+                forward.host_address, forward.container_port
+            ));
+        }
+
+        if let Some(proc) = &self.proc {
+            if proc.hidepid.is_some() || proc.gid.is_some() || proc.subset_pid {
+                args.unsupported("procfs options".into());
+            }
+            args.path("--proc", &proc.destination);
+        }
+        if let Some(dev) = &self.dev {
+            args.push(["--dev", "/dev"]);
+            for (node, wanted) in [("/dev/fuse", dev.fuse), ("/dev/kvm", dev.kvm)] {
+                if wanted {
+                    args.push(["--dev-bind-try", node, node]);
+                }
+            }
+        }
+
+        for op in &self.fs_ops {
+            match op {
+                config::FsOp::Mount(i) => self.mount_to_bwrap(&self.mounts[*i], &mut args),
+                config::FsOp::File { dest, .. } => args.unsupported(format!(
+                    "file {} (bwrap reads it from an fd)",
+                    dest.display()
+                )),
+                config::FsOp::Symlink { target, link } => {
+                    args.push([OsString::from("--symlink"), target.into(), link.into()])
+                }
+                config::FsOp::Dir { path, mode } => {
+                    args.push(["--perms".into(), format!("{:04o}", mode)]);
+                    args.path("--dir", path);
+                }
+                config::FsOp::RemountRo { path } => args.path("--remount-ro", path),
+            }
+        }
+        for path in &self.masked_paths {
+            args.unsupported(format!("mask {}", path.display()));
+        }
+        for path in &self.readonly_paths {
+            args.path("--remount-ro", path);
+        }
+        if self.etc_files.is_some() {
+            args.unsupported("generated /etc files".into());
+        }
+
+        if self.die_with_parent {
+            args.push(["--die-with-parent"]);
+        }
+        if self.process.env_no_inheriting {
+            args.push(["--clearenv"]);
+        }
+        let mut env: Vec<_> = self.process.env.iter().collect();
+        env.sort_by(|a, b| a.0.cmp(b.0));
+        for (key, val) in env {
+            match val {
+                config::EnvVarItem::Set(val) => {
+                    args.push([OsString::from("--setenv"), key.clone(), val.clone()])
+                }
+                config::EnvVarItem::Clean => args.push([OsString::from("--unsetenv"), key.clone()]),
+            }
+        }
+        if let Some(cwd) = &self.process.cwd {
+            args.path("--chdir", cwd);
+        }
+        if let Some(user) = &self.process.user {
+            args.unsupported(format!("run as {}:{}", user.uid, user.gid));
+        }
+        let callbacks = self.callbacks.len()
+            + self.setup_callbacks.len()
+            + self.parent_callbacks.len()
+            + self.result_callback.is_some() as usize;
+        if callbacks > 0 {
+            args.unsupported(format!("{} callbacks", callbacks));
+        }
+
+        // bwrap needs a program, callbacks alone are marked above.
+        if self.process.bin.is_empty() {
+            if callbacks == 0 {
+                args.unsupported("no program".into());
+            }
+        } else {
+            args.push(["--"]);
+            args.push([self.process.bin.clone()]);
+            args.push(self.process.args.iter().cloned());
+        }
+        args.0
+    }
+
+    fn mount_to_bwrap(&self, mnt: &config::Mount, args: &mut BwrapArgs) {
+        let opts = MountOptions::parse(mnt);
+        if mnt.is_idmapped() {
+            args.unsupported(format!("idmapped mount on {}", mnt.destination.display()));
+        }
+        if opts.bind {
+            // bwrap adds nodev to all binds but --dev-bind.
+            let opt = match (
+                opts.readonly,
+                opts.flags.contains(rustix::mount::MountFlags::NODEV),
+            ) {
+                (true, _) => "--ro-bind",
+                (false, true) => "--bind",
+                (false, false) => "--dev-bind",
+            };
+            let source = mnt.source.clone().unwrap_or_default();
+            args.push([
+                OsString::from(opt),
+                source.into(),
+                mnt.destination.clone().into(),
+            ]);
+            return;
+        }
+        match mnt.typ.as_deref() {
+            Some("tmpfs") => {
+                for opt in &opts.data {
+                    match opt.split_once('=') {
+                        Some(("mode", mode)) => args.push(["--perms", mode]),
+                        Some(("size", size)) => args.push(["--size", size]),
+                        _ => args.unsupported(format!("tmpfs option {}", opt)),
+                    }
+                }
+                args.path("--tmpfs", &mnt.destination);
+            }
+            Some("mqueue") => args.path("--mqueue", &mnt.destination),
+            Some("proc") => args.path("--proc", &mnt.destination),
+            typ => args.unsupported(format!(
+                "{} mount on {}",
+                typ.unwrap_or("untyped"),
+                mnt.destination.display()
+            )),
+        }
+    }
+
+    /// List the steps the child performs, in order.
+    ///
+    /// Callbacks are listed as opaque steps, with their number only.
+    pub fn describe(&self) -> String {
+        let mut steps = Vec::new();
+        let list = |set: &config::NamespaceSet| {
+            namespaces(set)
+                .into_iter()
+                .map(|(name, ns)| match ns {
+                    config::NamespaceItem::Enter(fd) => format!("{} (fd {})", name, fd),
+                    _ => name.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        if self.die_with_parent {
+            steps.push("set the parent death signal to SIGKILL".to_string());
+        }
+        if !namespaces(&self.namespace_nsenter).is_empty() {
+            steps.push(format!(
+                "enter namespaces: {}",
+                list(&self.namespace_nsenter)
+            ));
+        }
+        if !namespaces(&self.namespace_unshare).is_empty() {
+            steps.push(format!(
+                "unshare namespaces: {}",
+                list(&self.namespace_unshare)
+            ));
+        }
+        if !self.uid_maps.is_empty() {
+            steps.push(format!("write uid map: {}", id_maps(&self.uid_maps)));
+        }
+        if !self.gid_maps.is_empty() {
+            steps.push(format!("write gid map: {}", id_maps(&self.gid_maps)));
+        }
+        if !(matches!(self.namespace_unshare.pid, config::NamespaceItem::None)
+            && matches!(self.namespace_nsenter.pid, config::NamespaceItem::None))
+        {
+            steps.push("fork into the pid namespace".into());
+        }
+
+        let mut parent = Vec::new();
+        if let config::NetworkConfig::Veth(veth) = &self.network {
+            parent.push(format!("move veth {} in", veth.container_name));
+        }
+        for forward in &self.port_forwards {
+            parent.push(format!(
+                "forward {} to port {}",
+                forward.host_address, forward.container_port
+            ));
+        }
+        if !self.parent_callbacks.is_empty() {
+            parent.push(format!(
+                "{} parent callbacks (opaque)",
+                self.parent_callbacks.len()
+            ));
+        }
+        if !parent.is_empty() {
+            steps.push(format!("wait for the parent to {}", parent.join(", ")));
+        }
+
+        match &self.network {
+            config::NetworkConfig::None => (),
+            config::NetworkConfig::LoopbackOnly => steps.push("bring up loopback".into()),
+            config::NetworkConfig::Veth(veth) => steps.push(format!(
+                "bring up loopback and {}{}",
+                veth.container_name,
+                veth.container_address
+                    .map(|(addr, len)| format!(" with {}/{}", addr, len))
+                    .unwrap_or_default()
+            )),
+        }
+        if let Some(name) = &self.hostname {
+            steps.push(format!("set hostname {}", name));
+        }
+        if !self.setup_callbacks.is_empty() {
+            steps.push(format!(
+                "{} setup callbacks (opaque)",
+                self.setup_callbacks.len()
+            ));
+        }
+
+        if let Some(overlay) = &self.overlay {
+            let lower: Vec<_> = overlay
+                .lower
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect();
+            steps.push(format!(
+                "use an overlay of {} as root, with {}",
+                lower.join(":"),
+                overlay
+                    .upper
+                    .as_ref()
+                    .map(|p| format!("changes kept in {}", p.display()))
+                    .unwrap_or_else(|| "changes on a tmpfs".into())
+            ));
+        } else if let Some(root) = &self.root {
+            steps.push(format!("use {} as root", root.path.display()));
+        } else if self.sandbox_mnt {
+            steps.push("use a tmpfs as root".into());
+        }
+        if let Some(proc) = &self.proc {
+            let mut opts = Vec::new();
+            if let Some(hidepid) = proc.hidepid {
+                opts.push(format!("hidepid={}", hidepid.as_str()));
+            }
+            if let Some(gid) = proc.gid {
+                opts.push(format!("gid={}", gid));
+            }
+            if proc.subset_pid {
+                opts.push("subset=pid".into());
+            }
+            steps.push(format!(
+                "mount proc on {}{}",
+                proc.destination.display(),
+                match opts.is_empty() {
+                    true => String::new(),
+                    false => format!(" ({})", opts.join(",")),
+                }
+            ));
+        }
+        if let Some(dev) = &self.dev {
+            let mut nodes = vec!["null", "zero", "full", "random", "urandom", "tty"];
+            if dev.fuse {
+                nodes.push("fuse");
+            }
+            if dev.kvm {
+                nodes.push("kvm");
+            }
+            steps.push(format!("mount /dev with {}, pts and shm", nodes.join(", ")));
+        }
+
+        for op in &self.fs_ops {
+            steps.push(match op {
+                config::FsOp::Mount(i) => {
+                    let mnt = &self.mounts[*i];
+                    let opts = mnt.options.as_deref().unwrap_or_default().join(",");
+                    match (mnt.is_bind(), &mnt.source) {
+                        (true, Some(source)) => format!(
+                            "bind {} on {} ({})",
+                            source.display(),
+                            mnt.destination.display(),
+                            opts
+                        ),
+                        _ => format!(
+                            "mount {} on {}{}",
+                            mnt.typ.as_deref().unwrap_or("untyped"),
+                            mnt.destination.display(),
+                            match opts.is_empty() {
+                                true => String::new(),
+                                false => format!(" ({})", opts),
+                            }
+                        ),
+                    }
+                }
+                config::FsOp::File { dest, data, mode } => format!(
+                    "bind a staged file on {} ({}, mode {:o})",
+                    dest.display(),
+                    match data {
+                        config::FileData::Bytes(data) => format!("{} bytes", data.len()),
+                        config::FileData::Fd(_) => "from an fd".into(),
+                    },
+                    mode
+                ),
+                config::FsOp::Symlink { target, link } => {
+                    format!("symlink {} -> {}", link.display(), target.display())
+                }
+                config::FsOp::Dir { path, mode } => {
+                    format!("create dir {} (mode {:o})", path.display(), mode)
+                }
+                config::FsOp::RemountRo { path } => {
+                    format!("remount {} readonly", path.display())
+                }
+            });
+        }
+        for path in &self.masked_paths {
+            steps.push(format!("mask {}", path.display()));
+        }
+        for path in &self.readonly_paths {
+            steps.push(format!("make {} readonly", path.display()));
+        }
+        if self.etc_files.is_some() {
+            steps.push("bind generated /etc/passwd, group, hosts and resolv.conf".into());
+        }
+        if self.root.as_ref().and_then(|r| r.readonly) == Some(true) {
+            steps.push("make the root readonly".into());
+        }
+        if !self.callbacks.is_empty() {
+            steps.push(format!("{} callbacks (opaque)", self.callbacks.len()));
+        }
+
+        if !self.process.bin.is_empty() {
+            let mut exec = format!("exec {:?}", self.process.bin);
+            for arg in &self.process.args {
+                exec.push_str(&format!(" {:?}", arg));
+            }
+            let mut env = Vec::new();
+            if self.process.env_no_inheriting {
+                env.push("clear".to_string());
+            }
+            let mut vars: Vec<_> = self.process.env.iter().collect();
+            vars.sort_by(|a, b| a.0.cmp(b.0));
+            for (key, val) in vars {
+                env.push(match val {
+                    config::EnvVarItem::Set(val) => format!("{:?}={:?}", key, val),
+                    config::EnvVarItem::Clean => format!("unset {:?}", key),
+                });
+            }
+            if !env.is_empty() {
+                exec.push_str(&format!(", env: {}", env.join(", ")));
+            }
+            if let Some(cwd) = &self.process.cwd {
+                exec.push_str(&format!(", in {}", cwd.display()));
+            }
+            if let Some(user) = &self.process.user {
+                exec.push_str(&format!(", as {}:{}", user.uid, user.gid));
+            }
+            steps.push(exec);
+        }
+
+        steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| format!("{}. {}\n", i + 1, step))
+            .collect()
+    }
+}
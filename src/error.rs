@@ -8,6 +8,43 @@ pub enum Error {
     CloneFailed(i32),
     #[error("Unix API lib failed: `{0}`")]
     OsErrno(i32),
+    #[error("Child setup failed at {0}: `{1}`")]
+    SetupFailed(String, i32),
+    #[error("Root is not an overlay with a persistent upper layer")]
+    NoUpperDir,
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Hook failed: {0}")]
+    HookFailed(String),
+    #[error("No result from the child: {0}")]
+    NoResult(String),
     #[error("unknown data store error")]
     Unknown,
 }
+
+impl Error {
+    /// Attach the name of the setup step that failed.
+    ///
+    /// Steps that already have a name are prefixed with `step`.
+    pub(crate) fn at(self, step: &str) -> Self {
+        match self {
+            Error::OsErrno(errno) => Error::SetupFailed(step.into(), errno),
+            Error::SetupFailed(inner, errno) => {
+                Error::SetupFailed(format!("{}: {}", step, inner), errno)
+            }
+            e => e,
+        }
+    }
+}
+
+impl From<rustix::io::Errno> for Error {
+    fn from(e: rustix::io::Errno) -> Self {
+        Error::OsErrno(e.raw_os_error())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::OsErrno(e.raw_os_error().unwrap_or(0))
+    }
+}
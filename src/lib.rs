@@ -17,18 +17,32 @@ use getset::{CopyGetters, Getters, Setters};
 use std::{
     collections::VecDeque,
     ffi::{OsStr, OsString},
-    os::{fd::RawFd, unix::process::ExitStatusExt},
-    path::Path,
+    net::SocketAddr,
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        unix::process::ExitStatusExt,
+    },
+    path::{Path, PathBuf},
 };
+pub mod changeset;
 pub mod config;
 pub mod core;
+mod describe;
 pub mod error;
+mod mount;
+pub mod net;
+#[cfg(feature = "serde")]
+pub mod oci;
+#[cfg(feature = "serde")]
+pub mod profile;
 pub mod util;
+pub mod validate;
 extern crate xdg;
 
 use crate::error::Error;
 
-pub use crate::core::WrapCbBox;
+pub use crate::core::{WrapCbBox, WrapParentCbBox, WrapSetupCbBox};
+pub use crate::describe::BWRAP_UNSUPPORTED;
 
 /// Main class of spawn process and execute functions.
 #[derive(Getters, Setters, CopyGetters, Default)]
@@ -40,16 +54,41 @@ pub struct Wrap<'a> {
     uid_maps: Vec<config::IdMap>,
     gid_maps: Vec<config::IdMap>,
     callbacks: VecDeque<WrapCbBox<'a>>,
+    setup_callbacks: VecDeque<WrapSetupCbBox<'a>>,
+    parent_callbacks: VecDeque<WrapParentCbBox<'a>>,
+    result_callback: Option<core::WrapResultCbBox<'a>>,
 
     namespace_nsenter: config::NamespaceSet,
     namespace_unshare: config::NamespaceSet,
 
     sandbox_mnt: bool,
+    sandbox: config::SandboxMnt,
+    network: config::NetworkConfig,
+    port_forwards: Vec<config::PortForward>,
+    hostname: Option<String>,
+    etc_files: Option<config::EtcFiles>,
+    overlay: Option<config::Overlay>,
+    masked_paths: Vec<PathBuf>,
+    readonly_paths: Vec<PathBuf>,
+    proc: Option<config::ProcOptions>,
+    dev: Option<config::DevConfig>,
+    fs_ops: Vec<config::FsOp>,
+    die_with_parent: bool,
+    /// Namespaces to enter, kept open for `namespace_nsenter`.
+    ns_fds: Vec<OwnedFd>,
 }
 
 /// The reference to the running child.
+///
+/// Port forwards stop when the child is waited for, or when this is dropped.
 pub struct Child {
     pid: rustix::process::Pid,
+    /// Port forwards, stopped when the child is waited for or dropped.
+    forwarders: Vec<net::Forwarder>,
+    overlay: Option<config::Overlay>,
+    /// Read end of the pipe the result of `Wrap::run()` comes through.
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
+    result: Option<OwnedFd>,
 }
 
 /// Exit status of the child.
@@ -75,6 +114,9 @@ impl<'a> Wrap<'a> {
     }
 
     /// Set command to execute
+    ///
+    /// If it cannot be executed, the child writes the error to stderr
+    /// and exits with `1`.
     pub fn program<S: AsRef<OsStr>>(&mut self, program: S) -> &mut Self {
         self.process.set_bin((&program).into());
         self
@@ -157,6 +199,15 @@ impl<'a> Wrap<'a> {
     /// This instance of Wrap will not be consumed, but it's
     /// queue of callback functions will be empty.
     pub fn spawn(&mut self) -> Result<Child, Error> {
+        let errors: Vec<_> = self
+            .validate()
+            .into_iter()
+            .filter(|d| d.severity == validate::Severity::Error)
+            .map(|d| d.message)
+            .collect();
+        if !errors.is_empty() {
+            return Err(Error::InvalidConfig(errors.join(", ")));
+        }
         let mut wrapcore = core::WrapInner {
             process: self.process.clone(),
             root: self.root.clone(),
@@ -164,14 +215,107 @@ impl<'a> Wrap<'a> {
             uid_maps: self.uid_maps.clone(),
             gid_maps: self.gid_maps.clone(),
             callbacks: VecDeque::new(),
+            setup_callbacks: VecDeque::new(),
+            parent_callbacks: VecDeque::new(),
+            result_callback: self.result_callback.take(),
             namespace_nsenter: self.namespace_nsenter.clone(),
             namespace_unshare: self.namespace_unshare.clone(),
-            sandbox_mnt: self.sandbox_mnt.clone(),
+            sandbox_mnt: self.sandbox_mnt,
+            sandbox: self.sandbox.clone(),
+            network: self.network.clone(),
+            port_forwards: self.port_forwards.clone(),
+            hostname: self.hostname.clone(),
+            etc_files: self.etc_files.clone(),
+            overlay: self.overlay.clone(),
+            masked_paths: self.masked_paths.clone(),
+            readonly_paths: self.readonly_paths.clone(),
+            proc: self.proc.clone(),
+            dev: self.dev.clone(),
+            fs_ops: self.fs_ops.clone(),
+            die_with_parent: self.die_with_parent,
+            sync: None,
+            report: None,
+            result: None,
+            parent_fds: Vec::new(),
+            mount_trees: Vec::new(),
+            file_sources: VecDeque::new(),
+            root_bind: None,
         };
         wrapcore.callbacks.append(&mut self.callbacks);
+        wrapcore.setup_callbacks.append(&mut self.setup_callbacks);
+        wrapcore.parent_callbacks.append(&mut self.parent_callbacks);
         Self::spawn_inner(wrapcore)
     }
 
+    /// Take a snapshot of everything this `Wrap` will do, except the
+    /// callbacks.
+    pub fn config(&self) -> config::WrapConfig {
+        config::WrapConfig {
+            process: self.process.clone(),
+            root: self.root.clone(),
+            mounts: self.mounts.clone(),
+            uid_maps: self.uid_maps.clone(),
+            gid_maps: self.gid_maps.clone(),
+            namespace_nsenter: self.namespace_nsenter.clone(),
+            namespace_unshare: self.namespace_unshare.clone(),
+            sandbox_mnt: self.sandbox_mnt,
+            sandbox: self.sandbox.clone(),
+            network: self.network.clone(),
+            port_forwards: self.port_forwards.clone(),
+            hostname: self.hostname.clone(),
+            etc_files: self.etc_files.clone(),
+            overlay: self.overlay.clone(),
+            masked_paths: self.masked_paths.clone(),
+            readonly_paths: self.readonly_paths.clone(),
+            proc: self.proc.clone(),
+            dev: self.dev.clone(),
+            fs_ops: self.fs_ops.clone(),
+            die_with_parent: self.die_with_parent,
+        }
+    }
+
+    /// Create a `Wrap` from a snapshot taken by [`Self::config()`].
+    ///
+    /// Mounts that are not in the order of filesystem setup steps, like
+    /// those of a handwritten config, are mounted after all steps.
+    pub fn from_config(mut cfg: config::WrapConfig) -> Self {
+        let mounts = cfg.mounts.len();
+        cfg.fs_ops
+            .retain(|op| !matches!(op, config::FsOp::Mount(idx) if *idx >= mounts));
+        for idx in 0..mounts {
+            if !cfg
+                .fs_ops
+                .iter()
+                .any(|op| matches!(op, config::FsOp::Mount(i) if *i == idx))
+            {
+                cfg.fs_ops.push(config::FsOp::Mount(idx));
+            }
+        }
+        Self {
+            process: cfg.process,
+            root: cfg.root,
+            mounts: cfg.mounts,
+            uid_maps: cfg.uid_maps,
+            gid_maps: cfg.gid_maps,
+            namespace_nsenter: cfg.namespace_nsenter,
+            namespace_unshare: cfg.namespace_unshare,
+            sandbox_mnt: cfg.sandbox_mnt,
+            sandbox: cfg.sandbox,
+            network: cfg.network,
+            port_forwards: cfg.port_forwards,
+            hostname: cfg.hostname,
+            etc_files: cfg.etc_files,
+            overlay: cfg.overlay,
+            masked_paths: cfg.masked_paths,
+            readonly_paths: cfg.readonly_paths,
+            proc: cfg.proc,
+            dev: cfg.dev,
+            fs_ops: cfg.fs_ops,
+            die_with_parent: cfg.die_with_parent,
+            ..Default::default()
+        }
+    }
+
     /// Executes the command and callback functions in a child process,
     /// waiting for it to finish and collecting its status.
     ///
@@ -180,6 +324,66 @@ impl<'a> Wrap<'a> {
         self.spawn()?.wait()
     }
 
+    /// Run `f` in a child process and return its result.
+    ///
+    /// `f` runs after the callbacks, instead of the program. Its result
+    /// is serialized to JSON and sent to the parent through a pipe, the
+    /// child is waited for before the result is returned. A result that
+    /// cannot be serialized is returned as [`Error::NoResult`].
+    /// The notes of [`Self::callback()`] apply.
+    ///
+    /// ```
+    /// use nswrap::Wrap;
+    /// use nswrap::config;
+    /// let mut wrap = Wrap::new();
+    /// wrap.unshare(config::NamespaceType::User);
+    /// let uid: u32 = wrap.run(|| nswrap::util::get_uid()).unwrap();
+    /// // Without an uid map, the child runs as the overflow user.
+    /// let overflow = std::fs::read_to_string("/proc/sys/kernel/overflowuid").unwrap();
+    /// assert_eq!(uid, overflow.trim().parse::<u32>().unwrap());
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn run<T, F>(&mut self, f: F) -> Result<T, Error>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned,
+        F: FnOnce() -> T + Send + 'a,
+    {
+        use std::io::Read;
+
+        // Tagged, so that a failed serialization is told apart from a result.
+        self.result_callback = Some(Box::new(move || {
+            let result = f();
+            serde_json::to_vec(&Ok::<_, String>(&result))
+                .or_else(|e| serde_json::to_vec(&Err::<(), _>(e.to_string())))
+                .unwrap_or_default()
+        }));
+        let child = self.spawn();
+        self.result_callback = None;
+        let mut child = child?;
+        let mut data = Vec::new();
+        let read = match child.result.take() {
+            Some(fd) => std::fs::File::from(fd).read_to_end(&mut data),
+            None => Ok(0),
+        };
+        let status = child.wait()?;
+        read?;
+        if data.is_empty() {
+            return Err(Error::NoResult(match (status.code(), status.signal()) {
+                (Some(code), _) => format!("the child exited with {}", code),
+                (_, Some(signal)) => format!("the child was killed by signal {}", signal),
+                _ => "the child stopped".into(),
+            }));
+        }
+        match serde_json::from_slice::<Result<T, String>>(&data) {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(e)) => Err(Error::NoResult(format!(
+                "cannot serialize the result: {}",
+                e
+            ))),
+            Err(e) => Err(Error::NoResult(e.to_string())),
+        }
+    }
+
     /**
     Add a callback to run in the child before execute the program.
 
@@ -229,8 +433,38 @@ impl<'a> Wrap<'a> {
         self
     }
 
+    /// Add a callback to run in the child before its root is changed.
+    ///
+    /// The callbacks run after the namespaces and the network are set up,
+    /// and see the filesystem of the parent. An error stops the child
+    /// and is returned by [`Self::spawn()`].
+    /// The notes of [`Self::callback()`] apply.
+    pub fn setup_callback<F>(&mut self, cb: F) -> &mut Self
+    where
+        F: FnOnce() -> Result<(), Error> + Send + 'static,
+    {
+        self.setup_callbacks.push_back(Box::new(cb));
+        self
+    }
+
+    /// Add a callback to run in the parent once the child entered its
+    /// namespaces, with the pid of the child.
+    ///
+    /// The child waits until all of them returned. An error stops the
+    /// child and is returned by [`Self::spawn()`].
+    pub fn parent_callback<F>(&mut self, cb: F) -> &mut Self
+    where
+        F: FnOnce(u32) -> Result<(), Error> + 'a,
+    {
+        self.parent_callbacks.push_back(Box::new(cb));
+        self
+    }
+
     /// Set new `namespace(7)` for child process.
     ///
+    /// Time namespaces are not supported, [`Self::spawn()`] fails with
+    /// [`Error::InvalidConfig`] for them.
+    ///
     /// ```
     /// use nswrap::Wrap;
     /// use nswrap::config;
@@ -250,6 +484,13 @@ impl<'a> Wrap<'a> {
         self.add_namespace(typ, config::NamespaceItem::Enter(pidfd))
     }
 
+    /// Like [`Self::nsenter()`], with `fd` kept open as long as this `Wrap`.
+    pub fn nsenter_fd(&mut self, typ: config::NamespaceType, fd: OwnedFd) -> &mut Self {
+        let raw = fd.as_raw_fd();
+        self.ns_fds.push(fd);
+        self.nsenter(typ, raw)
+    }
+
     /// Add some mount points and file path that application usually needs.
     ///
     /// This will require a mount namespace.
@@ -305,25 +546,282 @@ impl<'a> Wrap<'a> {
     ///
     /// This is required if a user what to use `Wrap` for mountpoint
     /// management
+    ///
+    /// A bind mount on `/` replaces the tmpfs, like `bwrap --bind / /`,
+    /// and the mounts, files, dirs and symlinks added before it are
+    /// skipped, as they would be hidden under it.
     pub fn sandbox_mnt(&mut self, opt: bool) -> &mut Self {
         self.sandbox_mnt = opt;
         self
     }
+
+    /// Like [`Self::sandbox_mnt()`], with a configured tmpfs.
+    ///
+    /// The host root stays reachable at `/oldroot` only until all
+    /// mounts are set up, then it is detached.
+    pub fn sandbox_mnt_config(&mut self, cfg: config::SandboxMnt) -> &mut Self {
+        self.sandbox_mnt = true;
+        self.sandbox = cfg;
+        self
+    }
+
+    /// Add a mount point, mounts are applied in the order they are added,
+    /// after the root dir is set up.
+    ///
+    /// This will require a mount namespace.
+    /// Bind mounts are cloned before the root dir is changed, so their
+    /// source is a path on the host, while `destination` is a path inside
+    /// the container. Set `uid_mappings` and `gid_mappings` to create an
+    /// idmapped bind mount, which requires root on the host.
+    pub fn mount(&mut self, mnt: config::Mount) -> &mut Self {
+        self.add_mount(mnt)
+    }
+
+    /// Bind mount `src` on the host to `dest` inside the container.
+    pub fn bind<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, src: P, dest: Q) -> &mut Self {
+        self.add_bind(src.as_ref(), dest.as_ref(), &["rbind"])
+    }
+
+    /// Bind mount `src` on the host read-only to `dest` inside the container.
+    pub fn ro_bind<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, src: P, dest: Q) -> &mut Self {
+        self.add_bind(src.as_ref(), dest.as_ref(), &["rbind", "ro"])
+    }
+
+    /// Mount a new procfs, which shows the processes of the PID namespace
+    /// of the container.
+    ///
+    /// This requires a mount namespace and a new PID namespace, whose
+    /// init process runs the callbacks and the program. Without root,
+    /// the kernel only allows this while a procfs without mounts on top
+    /// of it is visible, so it is mounted before the root dir is changed.
+    pub fn proc_mount(&mut self, opts: config::ProcOptions) -> &mut Self {
+        self.proc = Some(opts);
+        self
+    }
+
+    /// Create a file at `dest` inside the container, with `data` as
+    /// contents and `mode` as permissions, like `bwrap --file`.
+    ///
+    /// The contents are written to a private tmpfs before the root dir is
+    /// set up, and the file is bind-mounted at `dest` in order with mount
+    /// points. Nothing is written through to what is mounted at `dest`,
+    /// but a missing `dest` is created as an empty mount point, so use a
+    /// [`Self::sandbox_mnt()`] or overlay root.
+    pub fn file<P: AsRef<Path>, D: AsRef<[u8]>>(
+        &mut self,
+        dest: P,
+        data: D,
+        mode: u32,
+    ) -> &mut Self {
+        self.fs_ops.push(config::FsOp::File {
+            dest: dest.as_ref().to_path_buf(),
+            data: config::FileData::Bytes(data.as_ref().into()),
+            mode,
+        });
+        self
+    }
+
+    /// Like [`Self::file()`], with the contents read from `fd` until EOF,
+    /// and `0644` as permissions.
+    ///
+    /// The fd is read by the child during [`Self::spawn()`], which also
+    /// inherits every open fd, so the write ends of a pipe have to be
+    /// closed before. Each child reads a regular file from its start, but
+    /// a pipe is drained by the first one, later ones get an empty file.
+    pub fn file_from_fd<P: AsRef<Path>>(&mut self, dest: P, fd: OwnedFd) -> &mut Self {
+        self.fs_ops.push(config::FsOp::File {
+            dest: dest.as_ref().to_path_buf(),
+            data: config::FileData::Fd(fd.into()),
+            mode: 0o644,
+        });
+        self
+    }
+
+    /// Create a symlink at `link` inside the container, pointing to
+    /// `target`, like `bwrap --symlink`.
+    ///
+    /// Symlinks are created in order with mount points and files, after
+    /// the root dir is set up. An existing symlink to the same target
+    /// is kept.
+    pub fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, target: P, link: Q) -> &mut Self {
+        self.fs_ops.push(config::FsOp::Symlink {
+            target: target.as_ref().to_path_buf(),
+            link: link.as_ref().to_path_buf(),
+        });
+        self
+    }
+
+    /// Create a directory inside the container with `mode` as
+    /// permissions, like `bwrap --dir` together with `--perms`.
+    ///
+    /// Missing parents are created with `0755`.
+    pub fn dir<P: AsRef<Path>>(&mut self, path: P, mode: u32) -> &mut Self {
+        self.fs_ops.push(config::FsOp::Dir {
+            path: path.as_ref().to_path_buf(),
+            mode,
+        });
+        self
+    }
+
+    /// Make the mount at `path` inside the container read-only, like
+    /// `bwrap --remount-ro`.
+    ///
+    /// This is done in order with mount points and files, and does not
+    /// apply to the mounts below `path`, unlike [`Self::readonly_path()`].
+    pub fn remount_ro<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.fs_ops.push(config::FsOp::RemountRo {
+            path: path.as_ref().to_path_buf(),
+        });
+        self
+    }
+
+    /// Kill the child with `SIGKILL` when the thread that spawned it
+    /// exits, like `bwrap --die-with-parent`.
+    pub fn die_with_parent(&mut self, opt: bool) -> &mut Self {
+        self.die_with_parent = opt;
+        self
+    }
+
+    /// Mount a minimal `/dev` on a tmpfs, like `bwrap --dev`.
+    ///
+    /// Device nodes are created with `mknod(2)` if possible, and bind
+    /// mounted from the host otherwise, which is the case without root.
+    /// A new devpts instance provides `/dev/pts` and `/dev/ptmx`.
+    /// This requires a mount namespace.
+    pub fn dev(&mut self, cfg: config::DevConfig) -> &mut Self {
+        self.dev = Some(cfg);
+        self
+    }
+
+    /// Hide a path inside the container, like `maskedPaths` of OCI.
+    ///
+    /// Directories are covered with an empty read-only tmpfs, anything
+    /// else with `/dev/null`. Paths are masked after all mounts, so that
+    /// paths below a mounted `/proc` can be masked, and are skipped if
+    /// they do not exist.
+    pub fn mask_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.masked_paths.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Make a path inside the container read-only, like `readonlyPaths`
+    /// of OCI.
+    ///
+    /// This applies to all mounts below the path, and is skipped if the
+    /// path does not exist.
+    pub fn readonly_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.readonly_paths.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Mask and protect the paths below `/proc` and `/sys` that runc
+    /// does by default, see [`config::DEFAULT_MASKED_PATHS`] and
+    /// [`config::DEFAULT_READONLY_PATHS`].
+    pub fn default_masks(&mut self) -> &mut Self {
+        for path in config::DEFAULT_MASKED_PATHS {
+            self.mask_path(path);
+        }
+        for path in config::DEFAULT_READONLY_PATHS {
+            self.readonly_path(path);
+        }
+        self
+    }
+
+    /// Use a host directory as root dir inside namespace, like the root
+    /// of an OCI bundle.
+    ///
+    /// The directory is bind mounted, and the host root is detached
+    /// once bind mount sources are opened.
+    /// This replaces [`Self::sandbox_mnt()`] and requires a mount namespace.
+    pub fn root_dir<P: AsRef<Path>>(&mut self, path: P, readonly: bool) -> &mut Self {
+        self.set_root(config::Root {
+            path: path.as_ref().to_path_buf(),
+            readonly: Some(readonly),
+        })
+    }
+
+    /// Use an overlayfs as root dir inside namespace.
+    ///
+    /// `lower_dirs` are read-only layers, the first one is the top-most.
+    /// Writes go to a tmpfs, or to `upper/upper` if `upper` is given,
+    /// with `upper/work` as the overlayfs work dir.
+    /// This replaces [`Self::sandbox_mnt()`] and requires a mount namespace.
+    /// Without root, a user namespace and Linux 5.11 or newer are needed.
+    pub fn overlay_root<I, P>(&mut self, lower_dirs: I, upper: Option<PathBuf>) -> &mut Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        self.overlay = Some(config::Overlay {
+            lower: lower_dirs
+                .into_iter()
+                .map(|p| p.as_ref().to_path_buf())
+                .collect(),
+            upper,
+        });
+        self
+    }
+
+    /// Set the hostname inside a new UTS namespace.
+    ///
+    /// This will require an UTS namespace.
+    pub fn hostname<S: Into<String>>(&mut self, name: S) -> &mut Self {
+        self.hostname = Some(name.into());
+        self
+    }
+
+    /// Generate `/etc/passwd`, `/etc/group`, `/etc/hosts` and
+    /// `/etc/resolv.conf` inside the sandbox.
+    ///
+    /// The files are written to a private tmpfs and bind-mounted read-only
+    /// over their paths, which requires a [`Self::sandbox_mnt()`], overlay
+    /// or [`Self::root_dir()`] root. A root dir only gets empty mount points.
+    /// Every mapped id gets a user and a group, mapped ids whose host id
+    /// is the current user get the name of the current user.
+    pub fn etc_files(&mut self, cfg: config::EtcFiles) -> &mut Self {
+        self.etc_files = Some(cfg);
+        self
+    }
+
+    /// Configure the network inside a new network namespace.
+    ///
+    /// This will require a network namespace, see [`Self::unshare()`].
+    /// Setup is done in the child with `rtnetlink(7)`, no external
+    /// program is executed.
+    pub fn network(&mut self, cfg: config::NetworkConfig) -> &mut Self {
+        self.network = cfg;
+        self
+    }
+
+    /// Forward TCP connections from `host_addr` to `container_port`
+    /// on the loopback interface of the child's network namespace.
+    ///
+    /// The parent listens on `host_addr` and copies bytes in both
+    /// directions, a helper process that joined the child's namespaces
+    /// connects the sockets. This works without privileges, combine it with
+    /// [`config::NetworkConfig::LoopbackOnly`].
+    /// Forwarding stops when the [`Child`] is waited for or dropped.
+    pub fn forward_port(&mut self, host_addr: SocketAddr, container_port: u16) -> &mut Self {
+        self.port_forwards.push(config::PortForward {
+            host_address: host_addr,
+            container_port,
+        });
+        self
+    }
 }
 
 /// Public builder pattern method
-impl<'a> Wrap<'_> {
+impl Wrap<'_> {
     fn add_namespace(
         &mut self,
         typ: config::NamespaceType,
         ns: config::NamespaceItem,
     ) -> &mut Self {
-        let mut set;
-        match ns {
+        let set = match ns {
             config::NamespaceItem::None => return self,
-            config::NamespaceItem::Unshare => set = &mut self.namespace_unshare,
-            config::NamespaceItem::Enter(_) => set = &mut self.namespace_nsenter,
-        }
+            config::NamespaceItem::Unshare => &mut self.namespace_unshare,
+            config::NamespaceItem::Enter(_) => &mut self.namespace_nsenter,
+        };
         match typ {
             config::NamespaceType::Mount => set.mount = ns,
             config::NamespaceType::Cgroup => set.cgroup = ns,
@@ -332,9 +830,9 @@ impl<'a> Wrap<'_> {
             config::NamespaceType::User => set.user = ns,
             config::NamespaceType::Pid => set.pid = ns,
             config::NamespaceType::Network => set.network = ns,
-            config::NamespaceType::Time => unimplemented!(),
+            config::NamespaceType::Time => set.time = ns,
         }
-        return self;
+        self
     }
 
     /// Set the program that will be executed.
@@ -342,7 +840,7 @@ impl<'a> Wrap<'_> {
     /// If the user only wants to execute the callback functions,
     /// this function does not have to be called.
     fn set_process(&mut self, proc: config::Process) -> &mut Self {
-        self.process = proc.into();
+        self.process = proc;
         self
     }
 
@@ -353,10 +851,21 @@ impl<'a> Wrap<'_> {
 
     /// Add mount point
     fn add_mount(&mut self, mnt: config::Mount) -> &mut Self {
+        self.fs_ops.push(config::FsOp::Mount(self.mounts.len()));
         self.mounts.push(mnt);
         self
     }
 
+    fn add_bind(&mut self, src: &Path, dest: &Path, options: &[&str]) -> &mut Self {
+        self.add_mount(config::Mount {
+            destination: dest.to_path_buf(),
+            typ: None,
+            source: Some(src.to_path_buf()),
+            options: Some(options.iter().map(|o| o.to_string()).collect()),
+            ..Default::default()
+        })
+    }
+
     /// Add uidmap
     fn add_uid_map(&mut self, id_map: config::IdMap) -> &mut Self {
         self.uid_maps.push(id_map);
@@ -370,10 +879,35 @@ impl<'a> Wrap<'_> {
 }
 
 impl Child {
+    /// Process id of the child, as seen from this process.
+    ///
+    /// With a PID namespace, this is a process outside of it that
+    /// forwards signals to the first process inside.
+    pub fn id(&self) -> u32 {
+        self.pid.as_raw_nonzero().get() as u32
+    }
+
     pub fn wait(&mut self) -> Result<ExitStatus, Error> {
-        match rustix::process::waitpid(Some(self.pid), rustix::process::WaitOptions::empty()) {
-            Ok(r) => Ok(ExitStatus::new(r.unwrap())),
-            Err(err) => Err(Error::OsErrno(err.raw_os_error())),
+        let ret =
+            match rustix::process::waitpid(Some(self.pid), rustix::process::WaitOptions::empty()) {
+                Ok(r) => Ok(ExitStatus::new(r.unwrap())),
+                Err(err) => Err(Error::OsErrno(err.raw_os_error())),
+            };
+        self.forwarders.clear();
+        ret
+    }
+
+    /// List what the child added, modified and deleted in its overlayfs root.
+    ///
+    /// This requires a persistent upper layer, see [`Wrap::overlay_root()`],
+    /// and should be called after [`Self::wait()`] returned.
+    pub fn changeset(&self) -> Result<changeset::Changeset, Error> {
+        match &self.overlay {
+            Some(config::Overlay {
+                lower,
+                upper: Some(upper),
+            }) => changeset::Changeset::from_upper(&upper.join("upper"), lower),
+            _ => Err(Error::NoUpperDir),
         }
     }
 }
@@ -389,10 +923,9 @@ impl ExitStatus {
     }
 
     pub fn code(&self) -> Option<i32> {
-        match self.wait_status.exit_status() {
-            Some(r) => Some(i32::try_from(r).unwrap()),
-            None => None,
-        }
+        self.wait_status
+            .exit_status()
+            .map(|r| i32::try_from(r).unwrap())
     }
 
     pub fn success(&self) -> bool {
@@ -0,0 +1,666 @@
+//! Mount points inside the container.
+//!
+//! Mounts are made with the fd-based mount API: filesystems are created
+//! with `fsopen(2)`, `fsconfig(2)` and `fsmount(2)`, bind mounts are cloned
+//! with `open_tree(2)` before the root is changed, and both are attached
+//! with `move_mount(2)` afterwards, so their sources do not have to be
+//! reachable from the new root.
+//!
+//! On kernels without this API (before Linux 5.2) the legacy `mount(2)`
+//! is used instead.
+use crate::error::Error;
+use crate::{config, util};
+use linux_raw_sys::general::{AT_EMPTY_PATH, AT_RECURSIVE, MOUNT_ATTR_IDMAP, MOUNT_ATTR_RDONLY};
+use rustix::fs::CWD;
+use rustix::io::Errno;
+use rustix::mount::{
+    fsconfig_create, fsconfig_set_flag, fsconfig_set_string, fsmount, fsopen, move_mount,
+    open_tree, FsMountFlags, FsOpenFlags, MountAttrFlags, MountFlags, MoveMountFlags,
+    OpenTreeFlags,
+};
+use std::{
+    ffi::OsStr,
+    fs,
+    io::Write,
+    os::fd::{AsFd, AsRawFd, OwnedFd},
+    path::Path,
+};
+
+/// What a known option of a [`config::Mount`] does.
+enum MountOption {
+    Bind,
+    Rbind,
+    Flag(MountFlags),
+    Ignored,
+}
+
+/// Options of a [`config::Mount`] that are not filesystem data.
+const OPTIONS: [(&str, MountOption); 23] = [
+    ("bind", MountOption::Bind),
+    ("rbind", MountOption::Rbind),
+    ("ro", MountOption::Flag(MountFlags::RDONLY)),
+    ("rw", MountOption::Ignored),
+    ("defaults", MountOption::Ignored),
+    // Propagation is set for all mounts, see `config::SandboxMnt`.
+    ("private", MountOption::Ignored),
+    ("rprivate", MountOption::Ignored),
+    ("shared", MountOption::Ignored),
+    ("rshared", MountOption::Ignored),
+    ("slave", MountOption::Ignored),
+    ("rslave", MountOption::Ignored),
+    ("unbindable", MountOption::Ignored),
+    ("runbindable", MountOption::Ignored),
+    ("nosuid", MountOption::Flag(MountFlags::NOSUID)),
+    ("nodev", MountOption::Flag(MountFlags::NODEV)),
+    ("noexec", MountOption::Flag(MountFlags::NOEXEC)),
+    ("noatime", MountOption::Flag(MountFlags::NOATIME)),
+    ("nodiratime", MountOption::Flag(MountFlags::NODIRATIME)),
+    ("relatime", MountOption::Flag(MountFlags::RELATIME)),
+    ("strictatime", MountOption::Flag(MountFlags::STRICTATIME)),
+    ("sync", MountOption::Flag(MountFlags::SYNCHRONOUS)),
+    ("dirsync", MountOption::Flag(MountFlags::DIRSYNC)),
+    ("silent", MountOption::Flag(MountFlags::SILENT)),
+];
+
+/// Parsed `options` of a [`config::Mount`].
+pub(crate) struct MountOptions {
+    pub(crate) flags: MountFlags,
+    /// Options passed to the filesystem.
+    pub(crate) data: Vec<String>,
+    pub(crate) bind: bool,
+    pub(crate) recursive: bool,
+    pub(crate) readonly: bool,
+}
+
+impl MountOptions {
+    pub(crate) fn new(flags: MountFlags) -> Self {
+        Self {
+            flags,
+            data: Vec::new(),
+            bind: false,
+            recursive: false,
+            readonly: flags.contains(MountFlags::RDONLY),
+        }
+    }
+
+    /// Options known by [`Self::parse()`], others are filesystem data.
+    #[cfg(feature = "serde")]
+    pub(crate) fn known() -> impl Iterator<Item = &'static str> {
+        OPTIONS.iter().map(|(name, _)| *name)
+    }
+
+    pub(crate) fn parse(mnt: &config::Mount) -> Self {
+        let mut o = Self::new(MountFlags::empty());
+        for opt in mnt.options.iter().flatten() {
+            match OPTIONS.iter().find(|(name, _)| name == opt) {
+                Some((_, MountOption::Bind)) => o.bind = true,
+                Some((_, MountOption::Rbind)) => {
+                    o.bind = true;
+                    o.recursive = true;
+                }
+                Some((_, MountOption::Flag(flag))) => {
+                    o.readonly |= flag.contains(MountFlags::RDONLY);
+                    o.flags |= *flag;
+                }
+                Some((_, MountOption::Ignored)) => (),
+                None => o.data.push(opt.to_string()),
+            }
+        }
+        o
+    }
+
+    /// Flags that `fsmount(2)` sets on the mount, as opposed to the superblock.
+    fn attr_flags(&self) -> MountAttrFlags {
+        let mut attr = MountAttrFlags::empty();
+        for (flag, a) in [
+            (MountFlags::RDONLY, MountAttrFlags::MOUNT_ATTR_RDONLY),
+            (MountFlags::NOSUID, MountAttrFlags::MOUNT_ATTR_NOSUID),
+            (MountFlags::NODEV, MountAttrFlags::MOUNT_ATTR_NODEV),
+            (MountFlags::NOEXEC, MountAttrFlags::MOUNT_ATTR_NOEXEC),
+            (MountFlags::NOATIME, MountAttrFlags::MOUNT_ATTR_NOATIME),
+            (
+                MountFlags::NODIRATIME,
+                MountAttrFlags::MOUNT_ATTR_NODIRATIME,
+            ),
+            (
+                MountFlags::STRICTATIME,
+                MountAttrFlags::MOUNT_ATTR_STRICTATIME,
+            ),
+        ] {
+            if self.flags.contains(flag) {
+                attr |= a;
+            }
+        }
+        attr
+    }
+}
+
+/// Source of a bind mount, opened before the root dir is changed.
+pub(crate) enum BindSource {
+    /// A detached clone of the source tree.
+    Tree(OwnedFd),
+    /// An `O_PATH` fd of the source, bound with `mount(2)` on kernels
+    /// without `open_tree(2)`.
+    Path(OwnedFd),
+}
+
+impl BindSource {
+    /// Open `source` and clone it as a detached mount if possible.
+    pub(crate) fn open(source: &Path, recursive: bool) -> Result<Self, Error> {
+        let fd = open_source(source)?;
+        match clone_tree(&fd, recursive) {
+            Ok(tree) => Ok(Self::Tree(tree)),
+            Err(e) if is_unsupported(&e) => Ok(Self::Path(fd)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Open the source of a bind mount as an `O_PATH` fd.
+///
+/// The path is resolved once, here, so swapping a component for a
+/// symlink later has no effect on what gets mounted. Any type of file
+/// can be bound, sockets and FIFOs included, but a symlink as the last
+/// component is not followed and fails with `ELOOP`.
+pub(crate) fn open_source(source: &Path) -> Result<OwnedFd, Error> {
+    use rustix::fs::{fstat, open, FileType, Mode, OFlags};
+    let fd = open(
+        source,
+        OFlags::PATH | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+    )?;
+    match FileType::from_raw_mode(fstat(&fd)?.st_mode) {
+        FileType::Symlink => Err(Error::OsErrno(libc::ELOOP)),
+        _ => Ok(fd),
+    }
+}
+
+/// Clone the tree at an opened source as a detached mount.
+pub(crate) fn clone_tree(source: &OwnedFd, recursive: bool) -> Result<OwnedFd, Error> {
+    let mut flags = OpenTreeFlags::OPEN_TREE_CLONE
+        | OpenTreeFlags::OPEN_TREE_CLOEXEC
+        | OpenTreeFlags::AT_EMPTY_PATH;
+    if recursive {
+        flags |= OpenTreeFlags::AT_RECURSIVE;
+    }
+    Ok(open_tree(source.as_fd(), "", flags)?)
+}
+
+fn setattr_flags(recursive: bool) -> u32 {
+    match recursive {
+        true => AT_EMPTY_PATH | AT_RECURSIVE,
+        false => AT_EMPTY_PATH,
+    }
+}
+
+/// Make a detached mount read-only.
+pub(crate) fn set_readonly(tree: &OwnedFd, recursive: bool) -> Result<(), Error> {
+    set_attr(tree, MOUNT_ATTR_RDONLY as u64, recursive)
+}
+
+fn set_attr(tree: &OwnedFd, attr_set: u64, recursive: bool) -> Result<(), Error> {
+    let attr = util::mount_attr {
+        attr_set,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    util::mount_setattr(tree.as_raw_fd(), setattr_flags(recursive), &attr)
+}
+
+/// Flags of a bind mount, which keeps those of its source otherwise.
+const BIND_FLAGS: MountFlags = MountFlags::RDONLY
+    .union(MountFlags::NOSUID)
+    .union(MountFlags::NODEV)
+    .union(MountFlags::NOEXEC);
+
+/// Set the read-only, `nosuid`, `nodev` and `noexec` flags of `opts` on
+/// a detached bind mount.
+pub(crate) fn set_bind_flags(tree: &OwnedFd, opts: &MountOptions) -> Result<(), Error> {
+    let attr = MountOptions::new(opts.flags & BIND_FLAGS).attr_flags();
+    match attr.is_empty() {
+        true => Ok(()),
+        false => set_attr(tree, attr.bits() as u64, opts.recursive),
+    }
+}
+
+/// Apply the id mapping of the user namespace `userns` to a detached mount.
+pub(crate) fn set_idmap(tree: &OwnedFd, userns: &OwnedFd, recursive: bool) -> Result<(), Error> {
+    let attr = util::mount_attr {
+        attr_set: MOUNT_ATTR_IDMAP as u64,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: userns.as_raw_fd() as u64,
+    };
+    util::mount_setattr(tree.as_raw_fd(), setattr_flags(recursive), &attr)
+}
+
+/// Attach a detached mount at `dest`, creating the mount point if needed.
+pub(crate) fn attach_tree(tree: &OwnedFd, dest: &Path) -> Result<(), Error> {
+    let is_dir = rustix::fs::fstat(tree)?.st_mode & libc::S_IFMT == libc::S_IFDIR;
+    create_mount_point(dest, is_dir)?;
+    move_mount(
+        tree.as_fd(),
+        "",
+        CWD,
+        dest,
+        MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH,
+    )?;
+    Ok(())
+}
+
+/// Create an empty directory or file at `dest` if nothing exists there.
+pub(crate) fn create_mount_point(dest: &Path, is_dir: bool) -> Result<(), Error> {
+    if fs::symlink_metadata(dest).is_ok() {
+        return Ok(());
+    }
+    if is_dir {
+        fs::create_dir_all(dest)?;
+    } else {
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::File::create(dest)?;
+    }
+    Ok(())
+}
+
+/// Whether an error means the fd-based mount API is not available.
+pub(crate) fn is_unsupported(e: &Error) -> bool {
+    matches!(e, Error::OsErrno(errno) | Error::SetupFailed(_, errno) if *errno == libc::ENOSYS)
+}
+
+/// Read the messages the kernel logged on a filesystem context.
+fn read_fs_log(fs: &OwnedFd) -> Vec<String> {
+    let mut log = Vec::new();
+    let mut buf = [0u8; 256];
+    // Each read returns one message, until `ENODATA`.
+    while let Ok(n) = rustix::io::read(fs, &mut buf) {
+        if n == 0 {
+            break;
+        }
+        log.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+    }
+    log
+}
+
+/// Turn a failed `fsconfig(2)` call into an error with the kernel's log.
+fn fs_error(fs: &OwnedFd, what: &str, e: Errno) -> Error {
+    let log = read_fs_log(fs);
+    let mut msg = what.to_string();
+    // Messages are prefixed with `e `, `w ` or `i ` for their severity.
+    for line in log.iter().filter(|l| l.starts_with("e ")) {
+        msg.push_str(": ");
+        msg.push_str(&line[2..]);
+    }
+    Error::SetupFailed(msg, e.raw_os_error())
+}
+
+/// Create a detached mount of a new `typ` filesystem.
+///
+/// Every option is passed with its own `fsconfig(2)` call, so a bad one
+/// is reported by name, along with what the filesystem logged about it.
+pub(crate) fn fs_tree(
+    typ: &str,
+    source: Option<&OsStr>,
+    opts: &MountOptions,
+) -> Result<OwnedFd, Error> {
+    let fs = fsopen(typ, FsOpenFlags::FSOPEN_CLOEXEC)?;
+    if let Some(source) = source {
+        fsconfig_set_string(fs.as_fd(), "source", source)
+            .map_err(|e| fs_error(&fs, "fsconfig source", e))?;
+    }
+    for (flag, key) in [
+        (MountFlags::SYNCHRONOUS, "sync"),
+        (MountFlags::DIRSYNC, "dirsync"),
+    ] {
+        if opts.flags.contains(flag) {
+            fsconfig_set_flag(fs.as_fd(), key)
+                .map_err(|e| fs_error(&fs, &format!("fsconfig {}", key), e))?;
+        }
+    }
+    for opt in &opts.data {
+        let res = match opt.split_once('=') {
+            Some((key, value)) => fsconfig_set_string(fs.as_fd(), key, value),
+            None => fsconfig_set_flag(fs.as_fd(), opt.as_str()),
+        };
+        res.map_err(|e| fs_error(&fs, &format!("fsconfig {}", opt), e))?;
+    }
+    fsconfig_create(fs.as_fd()).map_err(|e| fs_error(&fs, &format!("create {}", typ), e))?;
+    Ok(fsmount(
+        fs.as_fd(),
+        FsMountFlags::FSMOUNT_CLOEXEC,
+        opts.attr_flags(),
+    )?)
+}
+
+/// Mount a new `typ` filesystem at `dest`.
+pub(crate) fn mount_fs(
+    typ: &str,
+    source: Option<&OsStr>,
+    dest: &Path,
+    opts: &MountOptions,
+) -> Result<(), Error> {
+    match fs_tree(typ, source, opts) {
+        Ok(tree) => attach_tree(&tree, dest),
+        Err(e) if is_unsupported(&e) => legacy_mount_fs(typ, source, dest, opts),
+        Err(e) => Err(e),
+    }
+}
+
+/// Mount the filesystem of a [`config::Mount`] at its destination.
+pub(crate) fn mount_config(mnt: &config::Mount, opts: &MountOptions) -> Result<(), Error> {
+    let typ = mnt.typ.as_deref().unwrap_or("");
+    let source = mnt.source.as_deref().map(|p| p.as_os_str());
+    mount_fs(typ, source, &mnt.destination, opts)
+}
+
+/// Mount a filesystem with `mount(2)`.
+fn legacy_mount_fs(
+    typ: &str,
+    source: Option<&OsStr>,
+    dest: &Path,
+    opts: &MountOptions,
+) -> Result<(), Error> {
+    create_mount_point(dest, true)?;
+    rustix::mount::mount(
+        source.unwrap_or(typ.as_ref()),
+        dest,
+        typ,
+        opts.flags,
+        opts.data.join(","),
+    )?;
+    Ok(())
+}
+
+/// Bind mount `source` at `dest` with `mount(2)`.
+///
+/// Unlike [`set_bind_flags()`], the remount with the flags only applies
+/// to the top mount, not to mounts below it.
+pub(crate) fn legacy_bind(source: &Path, dest: &Path, opts: &MountOptions) -> Result<(), Error> {
+    create_mount_point(dest, fs::metadata(source)?.is_dir())?;
+    match opts.recursive {
+        true => rustix::mount::mount_recursive_bind(source, dest)?,
+        false => rustix::mount::mount_bind(source, dest)?,
+    }
+    let flags = opts.flags & BIND_FLAGS;
+    if !flags.is_empty() {
+        rustix::mount::mount_remount(dest, MountFlags::BIND | flags, "")?;
+    }
+    Ok(())
+}
+
+fn proc_options(proc: &config::ProcOptions, legacy: bool) -> MountOptions {
+    let mut opts = MountOptions::new(MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC);
+    match proc.hidepid {
+        // Kernels before 5.8 only know the numeric values.
+        Some(hidepid) if legacy => opts.data.push(format!("hidepid={}", hidepid as u8)),
+        Some(hidepid) => opts.data.push(format!("hidepid={}", hidepid.as_str())),
+        None => (),
+    }
+    if let Some(gid) = proc.gid {
+        opts.data.push(format!("gid={}", gid));
+    }
+    if proc.subset_pid && !legacy {
+        opts.data.push("subset=pid".into());
+    }
+    opts
+}
+
+/// Create a detached procfs mount of the current PID namespace.
+pub(crate) fn proc_tree(proc: &config::ProcOptions) -> Result<OwnedFd, Error> {
+    fs_tree("proc", Some("proc".as_ref()), &proc_options(proc, false))
+}
+
+/// Mount procfs with `mount(2)`, on kernels without [`proc_tree()`].
+///
+/// `subset=pid` is dropped, it was added after the new mount API.
+pub(crate) fn legacy_mount_proc(proc: &config::ProcOptions) -> Result<(), Error> {
+    legacy_mount_fs(
+        "proc",
+        Some("proc".as_ref()),
+        &proc.destination,
+        &proc_options(proc, true),
+    )
+}
+
+/// A host device node, opened before the root dir is changed.
+pub(crate) struct DevNode {
+    name: &'static str,
+    rdev: u64,
+    mode: u32,
+    pub(crate) source: BindSource,
+}
+
+impl DevNode {
+    pub(crate) fn path(&self, dev: &Path) -> std::path::PathBuf {
+        dev.join(self.name)
+    }
+}
+
+/// Open the device nodes of a [`config::DevConfig`] on the host.
+///
+/// Optional devices the host does not have are skipped.
+pub(crate) fn open_dev_nodes(cfg: &config::DevConfig) -> Result<Vec<DevNode>, Error> {
+    let mut names = vec!["null", "zero", "full", "random", "urandom", "tty"];
+    if cfg.fuse {
+        names.push("fuse");
+    }
+    if cfg.kvm {
+        names.push("kvm");
+    }
+    let mut nodes = Vec::new();
+    for name in names {
+        let path = Path::new("/dev").join(name);
+        let source = match BindSource::open(&path, false) {
+            Ok(source) => source,
+            Err(Error::OsErrno(libc::ENOENT)) if name == "fuse" || name == "kvm" => continue,
+            Err(e) => return Err(e.at(&path.display().to_string())),
+        };
+        let stat = match &source {
+            BindSource::Tree(fd) | BindSource::Path(fd) => rustix::fs::fstat(fd)?,
+        };
+        nodes.push(DevNode {
+            name,
+            rdev: stat.st_rdev,
+            mode: stat.st_mode & 0o777,
+            source,
+        });
+    }
+    Ok(nodes)
+}
+
+/// Mount a tmpfs on `dev` with the links and directories of `/dev`,
+/// and a new devpts instance.
+pub(crate) fn mount_dev_tmpfs(dev: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::symlink;
+
+    let mut opts = MountOptions::new(MountFlags::NOSUID);
+    opts.data.push("mode=755".into());
+    mount_fs("tmpfs", Some("tmpfs".as_ref()), dev, &opts)?;
+
+    for (link, target) in [
+        ("fd", "/proc/self/fd"),
+        ("stdin", "/proc/self/fd/0"),
+        ("stdout", "/proc/self/fd/1"),
+        ("stderr", "/proc/self/fd/2"),
+        ("core", "/proc/kcore"),
+        ("ptmx", "pts/ptmx"),
+    ] {
+        symlink(target, dev.join(link))?;
+    }
+    fs::create_dir(dev.join("shm"))?;
+
+    let mut opts = MountOptions::new(MountFlags::NOSUID | MountFlags::NOEXEC);
+    opts.data = vec![
+        "newinstance".into(),
+        "ptmxmode=0666".into(),
+        "mode=620".into(),
+    ];
+    mount_fs("devpts", Some("devpts".as_ref()), &dev.join("pts"), &opts)
+}
+
+/// Create a device node below `dev` with `mknod(2)`.
+///
+/// Returns `false` if that is not permitted, usually without root on
+/// the host, so that the host node has to be bound instead.
+pub(crate) fn mknod_dev_node(dev: &Path, node: &DevNode) -> Result<bool, Error> {
+    use rustix::fs::{chmod, mknodat, FileType, Mode};
+    let path = dev.join(node.name);
+    let mode = Mode::from_raw_mode(node.mode);
+    match mknodat(CWD, &path, FileType::CharacterDevice, mode, node.rdev) {
+        Ok(()) => (),
+        Err(Errno::PERM) => return Ok(false),
+        Err(e) => return Err(e.into()),
+    }
+    // The mode was masked by the umask.
+    chmod(&path, mode)?;
+    Ok(true)
+}
+
+/// Make the mount at `path` read-only, without the mounts below it.
+pub(crate) fn remount_readonly(path: &Path) -> Result<(), Error> {
+    let fd = open_source(path)?;
+    match set_readonly(&fd, false) {
+        Err(e) if is_unsupported(&e) => {
+            rustix::mount::mount_remount(path, MountFlags::BIND | MountFlags::RDONLY, "")?;
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+/// Hide `path` under an empty read-only tmpfs if it is a directory,
+/// or under `dev_null` otherwise.
+///
+/// Missing paths are skipped.
+pub(crate) fn mask_path(path: &Path, dev_null: &OwnedFd) -> Result<(), Error> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if meta.is_dir() {
+        let opts = MountOptions::new(MountFlags::RDONLY | MountFlags::NOSUID | MountFlags::NODEV);
+        return mount_fs("tmpfs", Some("tmpfs".as_ref()), path, &opts);
+    }
+    match clone_tree(dev_null, false) {
+        Ok(tree) => attach_tree(&tree, path),
+        Err(e) if is_unsupported(&e) => {
+            let source = format!("/proc/self/fd/{}", dev_null.as_raw_fd());
+            legacy_bind(
+                source.as_ref(),
+                path,
+                &MountOptions::new(MountFlags::empty()),
+            )
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Make `path` and all mounts below it read-only.
+///
+/// Missing paths are skipped.
+pub(crate) fn readonly_path(path: &Path) -> Result<(), Error> {
+    let source = match open_source(path) {
+        Ok(fd) => fd,
+        Err(Error::OsErrno(libc::ENOENT)) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    match clone_tree(&source, true) {
+        Ok(tree) => {
+            set_readonly(&tree, true)?;
+            attach_tree(&tree, path)
+        }
+        Err(e) if is_unsupported(&e) => {
+            let mut opts = MountOptions::new(MountFlags::RDONLY);
+            opts.recursive = true;
+            legacy_bind(path, path, &opts)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Translate `size` ids from `id` inside the container to the ids on the host.
+///
+/// All of them have to be mapped by one of `maps`, unless there are none.
+pub(crate) fn to_host(id: u32, size: u32, maps: &[config::IdMap]) -> Result<u32, Error> {
+    if maps.is_empty() {
+        return Ok(id);
+    }
+    maps.iter()
+        .find(|m| {
+            id >= m.container_id && (id - m.container_id) as u64 + size as u64 <= m.size as u64
+        })
+        .map(|m| m.host_id + (id - m.container_id))
+        .ok_or_else(|| {
+            Error::InvalidConfig(format!(
+                "ids {}..{} are not mapped in the user namespace",
+                id,
+                id as u64 + size as u64
+            ))
+        })
+}
+
+/// Create a user namespace whose mapping implements the id mapping
+/// of an idmapped mount.
+///
+/// In an idmapped mount, ids on the source filesystem are looked up in the
+/// namespace, so its map goes from `host_id` to the host id of
+/// `container_id`, which is found with the maps of the container itself.
+/// This has to be done by a process that may write these maps,
+/// usually root on the host.
+pub(crate) fn idmap_userns(
+    mnt: &config::Mount,
+    uid_maps: &[config::IdMap],
+    gid_maps: &[config::IdMap],
+) -> Result<OwnedFd, Error> {
+    let render = |maps: &[config::IdMap], container: &[config::IdMap]| {
+        maps.iter()
+            .map(|m| {
+                let id = to_host(m.container_id, m.size, container)?;
+                Ok(format!("{} {} {}\n", m.host_id, id, m.size))
+            })
+            .collect::<Result<String, Error>>()
+    };
+    let uid_map = render(&mnt.uid_mappings, uid_maps)?;
+    let gid_map = render(&mnt.gid_mappings, gid_maps)?;
+
+    // A helper that only lives in the new user namespace until
+    // the maps are written and the namespace is opened.
+    let mut stack: Box<[u8; crate::core::STACK_SIZE]> = Box::new([0; crate::core::STACK_SIZE]);
+    let cb = Box::new(move || -> isize {
+        loop {
+            unsafe { libc::pause() };
+        }
+    });
+    let pid = unsafe {
+        util::clone(
+            cb,
+            &mut *stack,
+            util::CloneFlags::NEWUSER,
+            Some(libc::SIGCHLD),
+        )
+    }?;
+
+    let write = |file: &str, content: &str| -> Result<(), Error> {
+        if content.is_empty() {
+            return Ok(());
+        }
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .open(format!("/proc/{}/{}", pid, file))?;
+        f.write_all(content.as_bytes())?;
+        Ok(())
+    };
+    let res = write("uid_map", &uid_map)
+        .and_then(|_| write("gid_map", &gid_map))
+        .and_then(|_| Ok(fs::File::open(format!("/proc/{}/ns/user", pid))?));
+
+    let pid = rustix::process::Pid::from_raw(pid as i32);
+    if let Some(pid) = pid {
+        let _ = rustix::process::kill_process(pid, rustix::process::Signal::Kill);
+    }
+    let _ = rustix::process::waitpid(pid, rustix::process::WaitOptions::empty());
+    Ok(res?.into())
+}
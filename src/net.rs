@@ -0,0 +1,686 @@
+/*!
+A minimal `rtnetlink(7)` client.
+
+Only the few requests that nswrap needs to set up a network namespace
+are implemented, so that no external program like `ip(8)` has to be
+present inside the container.
+*/
+use crate::error::Error;
+use linux_raw_sys::netlink::{
+    ifaddrmsg, ifinfomsg, nlmsgerr, nlmsghdr, rt_class_t::RT_TABLE_MAIN,
+    rt_scope_t::RT_SCOPE_UNIVERSE, rtattr_type_t::RTA_GATEWAY, rtattr_type_t::RTA_OIF, rtmsg,
+    IFA_ADDRESS, IFA_LOCAL, IFLA_IFNAME, IFLA_INFO_DATA, IFLA_INFO_KIND, IFLA_LINKINFO, IFLA_MTU,
+    IFLA_NET_NS_PID, NETLINK_ROUTE, NLMSG_ERROR, NLM_F_ACK, NLM_F_CREATE, NLM_F_EXCL,
+    NLM_F_REQUEST, RTM_NEWADDR, RTM_NEWLINK, RTM_NEWROUTE, RTN_UNICAST, RTPROT_BOOT,
+};
+use std::{
+    ffi::CString,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener, TcpStream},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+/// `VETH_INFO_PEER` from `linux/veth.h`
+const VETH_INFO_PEER: u16 = 1;
+
+/// Netlink messages and attributes are aligned to 4 bytes.
+const NLMSG_ALIGNTO: usize = 4;
+
+fn align(len: usize) -> usize {
+    (len + NLMSG_ALIGNTO - 1) & !(NLMSG_ALIGNTO - 1)
+}
+
+fn last_errno() -> Error {
+    Error::OsErrno(unsafe { *libc::__errno_location() })
+}
+
+/// View a plain C struct as bytes.
+fn as_bytes<T: Copy>(v: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(v as *const T as *const u8, std::mem::size_of::<T>()) }
+}
+
+/// A netlink request under construction.
+pub(crate) struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    /// Start a new request with the given type and extra `NLM_F_*` flags.
+    ///
+    /// `NLM_F_REQUEST` and `NLM_F_ACK` are always set.
+    pub(crate) fn new(typ: u16, flags: u16) -> Self {
+        let hdr = nlmsghdr {
+            nlmsg_len: 0,
+            nlmsg_type: typ,
+            nlmsg_flags: (NLM_F_REQUEST | NLM_F_ACK) as u16 | flags,
+            nlmsg_seq: 0,
+            nlmsg_pid: 0,
+        };
+        let mut s = Self { buf: Vec::new() };
+        s.push(&hdr);
+        s
+    }
+
+    /// Append a fixed size header such as `ifinfomsg`.
+    pub(crate) fn push<T: Copy>(&mut self, v: &T) -> &mut Self {
+        self.buf.extend_from_slice(as_bytes(v));
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+
+    /// Append a `rtattr` with raw payload.
+    pub(crate) fn attr(&mut self, typ: u16, data: &[u8]) -> &mut Self {
+        let len = (4 + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&typ.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+
+    /// Append a NUL terminated string attribute.
+    pub(crate) fn attr_str(&mut self, typ: u16, data: &str) -> &mut Self {
+        let mut v = data.as_bytes().to_vec();
+        v.push(0);
+        self.attr(typ, &v)
+    }
+
+    /// Start a nested attribute, the returned offset must be passed to
+    /// [`Self::end_nested()`] after all inner attributes are added.
+    pub(crate) fn begin_nested(&mut self, typ: u16) -> usize {
+        let start = self.buf.len();
+        self.attr(typ, &[]);
+        start
+    }
+
+    pub(crate) fn end_nested(&mut self, start: usize) -> &mut Self {
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        self
+    }
+
+    fn finish(&mut self, seq: u32) -> &[u8] {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        &self.buf
+    }
+}
+
+/// A `NETLINK_ROUTE` socket.
+pub struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    /// Open a route netlink socket in the current network namespace.
+    pub fn new() -> Result<Self, Error> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                NETLINK_ROUTE as i32,
+            )
+        };
+        if fd == -1 {
+            return Err(last_errno());
+        }
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            seq: 0,
+        })
+    }
+
+    /// Send a request and wait for the kernel to acknowledge it.
+    pub(crate) fn request(&mut self, msg: &mut Message) -> Result<(), Error> {
+        self.seq += 1;
+        let buf = msg.finish(self.seq);
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        let res = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                0,
+                &addr as *const _ as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if res == -1 {
+            return Err(last_errno());
+        }
+        self.wait_ack()
+    }
+
+    fn wait_ack(&mut self) -> Result<(), Error> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len == -1 {
+                return Err(last_errno());
+            }
+            let len = len as usize;
+            let mut off = 0;
+            while off + std::mem::size_of::<nlmsghdr>() <= len {
+                let hdr: nlmsghdr =
+                    unsafe { std::ptr::read_unaligned(buf[off..].as_ptr() as *const nlmsghdr) };
+                if hdr.nlmsg_seq == self.seq && hdr.nlmsg_type as u32 == NLMSG_ERROR {
+                    let err: nlmsgerr = unsafe {
+                        std::ptr::read_unaligned(
+                            buf[off + std::mem::size_of::<nlmsghdr>()..].as_ptr()
+                                as *const nlmsgerr,
+                        )
+                    };
+                    return match err.error {
+                        0 => Ok(()),
+                        e => Err(Error::OsErrno(-e)),
+                    };
+                }
+                if hdr.nlmsg_len == 0 {
+                    break;
+                }
+                off += align(hdr.nlmsg_len as usize);
+            }
+        }
+    }
+
+    /// Set the `IFF_UP` flag of a link, like `ip link set <dev> up`.
+    pub fn link_set_up(&mut self, index: u32) -> Result<(), Error> {
+        let info = ifinfomsg {
+            ifi_family: libc::AF_UNSPEC as u8,
+            __ifi_pad: 0,
+            ifi_type: 0,
+            ifi_index: index as i32,
+            ifi_flags: libc::IFF_UP as u32,
+            ifi_change: libc::IFF_UP as u32,
+        };
+        let mut msg = Message::new(RTM_NEWLINK as u16, 0);
+        msg.push(&info);
+        self.request(&mut msg)
+    }
+}
+
+impl Netlink {
+    /// Create a `veth(4)` pair, like
+    /// `ip link add <name> type veth peer <peer> netns <pid>`.
+    ///
+    /// If `peer_pid` is given, the peer is created directly in the
+    /// network namespace of that process.
+    pub fn veth_add(
+        &mut self,
+        name: &str,
+        peer: &str,
+        peer_pid: Option<u32>,
+        mtu: Option<u32>,
+    ) -> Result<(), Error> {
+        let info: ifinfomsg = unsafe { std::mem::zeroed() };
+        let mut msg = Message::new(RTM_NEWLINK as u16, (NLM_F_CREATE | NLM_F_EXCL) as u16);
+        msg.push(&info);
+        msg.attr_str(IFLA_IFNAME as u16, name);
+        if let Some(mtu) = mtu {
+            msg.attr(IFLA_MTU as u16, &mtu.to_ne_bytes());
+        }
+        let linkinfo = msg.begin_nested(IFLA_LINKINFO as u16);
+        msg.attr_str(IFLA_INFO_KIND as u16, "veth");
+        let data = msg.begin_nested(IFLA_INFO_DATA as u16);
+        let peer_info = msg.begin_nested(VETH_INFO_PEER);
+        msg.push(&info);
+        msg.attr_str(IFLA_IFNAME as u16, peer);
+        if let Some(mtu) = mtu {
+            msg.attr(IFLA_MTU as u16, &mtu.to_ne_bytes());
+        }
+        if let Some(pid) = peer_pid {
+            msg.attr(IFLA_NET_NS_PID as u16, &pid.to_ne_bytes());
+        }
+        msg.end_nested(peer_info);
+        msg.end_nested(data);
+        msg.end_nested(linkinfo);
+        self.request(&mut msg)
+    }
+
+    /// Add an address to a link, like `ip addr add <addr>/<prefix> dev <dev>`.
+    pub fn addr_add(&mut self, index: u32, addr: IpAddr, prefix: u8) -> Result<(), Error> {
+        let info = ifaddrmsg {
+            ifa_family: family(&addr),
+            ifa_prefixlen: prefix,
+            ifa_flags: 0,
+            ifa_scope: RT_SCOPE_UNIVERSE as u8,
+            ifa_index: index,
+        };
+        let mut msg = Message::new(RTM_NEWADDR as u16, (NLM_F_CREATE | NLM_F_EXCL) as u16);
+        msg.push(&info);
+        msg.attr(IFA_LOCAL as u16, &addr_bytes(&addr));
+        msg.attr(IFA_ADDRESS as u16, &addr_bytes(&addr));
+        self.request(&mut msg)
+    }
+
+    /// Add a default route, like `ip route add default via <gateway> dev <dev>`.
+    pub fn route_add_default(&mut self, index: u32, gateway: IpAddr) -> Result<(), Error> {
+        let info = rtmsg {
+            rtm_family: family(&gateway),
+            rtm_dst_len: 0,
+            rtm_src_len: 0,
+            rtm_tos: 0,
+            rtm_table: RT_TABLE_MAIN as u8,
+            rtm_protocol: RTPROT_BOOT as u8,
+            rtm_scope: RT_SCOPE_UNIVERSE as u8,
+            rtm_type: RTN_UNICAST as u8,
+            rtm_flags: 0,
+        };
+        let mut msg = Message::new(RTM_NEWROUTE as u16, (NLM_F_CREATE | NLM_F_EXCL) as u16);
+        msg.push(&info);
+        msg.attr(RTA_GATEWAY as u16, &addr_bytes(&gateway));
+        msg.attr(RTA_OIF as u16, &index.to_ne_bytes());
+        self.request(&mut msg)
+    }
+}
+
+fn family(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn addr_bytes(addr: &IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    }
+}
+
+/// Get the index of a network interface by its name.
+///
+/// See also [if_nametoindex(3)](https://man7.org/linux/man-pages/man3/if_nametoindex.3.html)
+pub fn if_nametoindex(name: &str) -> Result<u32, Error> {
+    let name = CString::new(name).map_err(|_| Error::OsErrno(libc::EINVAL))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(last_errno()),
+        idx => Ok(idx),
+    }
+}
+
+/// Bring up the loopback interface of the current network namespace.
+pub fn loopback_up() -> Result<(), Error> {
+    let index = if_nametoindex("lo")?;
+    Netlink::new()?.link_set_up(index)
+}
+
+/// Create the veth pair and configure the host end.
+///
+/// Called in the parent once the child has entered its network namespace.
+pub(crate) fn veth_host_setup(cfg: &crate::config::VethConfig, pid: u32) -> Result<(), Error> {
+    let mut nl = Netlink::new()?;
+    nl.veth_add(&cfg.host_name, &cfg.container_name, Some(pid), cfg.mtu)?;
+    let index = if_nametoindex(&cfg.host_name)?;
+    if let Some((addr, prefix)) = cfg.host_address {
+        nl.addr_add(index, addr, prefix)?;
+    }
+    nl.link_set_up(index)
+}
+
+/// Configure the container end of the veth pair.
+///
+/// Called in the child after the parent has moved the peer into
+/// its network namespace.
+pub(crate) fn veth_container_setup(cfg: &crate::config::VethConfig) -> Result<(), Error> {
+    loopback_up()?;
+    let mut nl = Netlink::new()?;
+    let index = if_nametoindex(&cfg.container_name)?;
+    if let Some((addr, prefix)) = cfg.container_address {
+        nl.addr_add(index, addr, prefix)?;
+    }
+    nl.link_set_up(index)?;
+    if let Some(gateway) = cfg.gateway {
+        nl.route_add_default(index, gateway)?;
+    }
+    Ok(())
+}
+
+/// Forwards connections accepted on a host listener to a port on the
+/// loopback interface of the network namespace of the child.
+///
+/// The sockets are connected by a helper process that joined the
+/// namespaces of the child, and sent back with `SCM_RIGHTS`. Accepting
+/// and copying run in threads of this process. Dropping it stops both,
+/// and shuts down the forwarded connections.
+pub(crate) struct Forwarder {
+    helper: libc::pid_t,
+    /// Closing it stops the accepting thread.
+    stop: Option<OwnedFd>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for Forwarder {
+    fn drop(&mut self) {
+        unsafe {
+            libc::kill(self.helper, libc::SIGKILL);
+            libc::waitpid(self.helper, std::ptr::null_mut(), 0);
+        }
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Start forwarding connections accepted on `listener` to `port` on the
+/// loopback interface of the network namespace of `pid`.
+///
+/// The helper joins the user namespace of `pid` first if `user_ns` is set,
+/// which gives an unprivileged parent the right to join the network namespace.
+pub(crate) fn spawn_forwarder(
+    listener: TcpListener,
+    port: u16,
+    pid: u32,
+    user_ns: bool,
+) -> Result<Forwarder, Error> {
+    use crate::util::{clone, CloneFlags};
+    let open_ns = |name: &str| -> Result<OwnedFd, Error> {
+        Ok(std::fs::File::open(format!("/proc/{}/ns/{}", pid, name))?.into())
+    };
+    let user = match user_ns {
+        true => Some(open_ns("user")?),
+        false => None,
+    };
+    let net = open_ns("net")?;
+    let target = match listener.local_addr() {
+        Ok(a) if a.is_ipv6() => std::net::SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port),
+        _ => std::net::SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+    };
+    let (addr, addr_len) = sockaddr(&target);
+
+    let mut fds = [0; 2];
+    if unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    } != 0
+    {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let (sock, helper_sock) =
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+    let helper = ConnectHelper {
+        sock: helper_sock.as_raw_fd(),
+        user: user.as_ref().map_or(-1, |fd| fd.as_raw_fd()),
+        net: net.as_raw_fd(),
+        family: addr.ss_family as libc::c_int,
+        addr,
+        addr_len,
+    };
+    let mut stack: Box<[u8; crate::core::STACK_SIZE]> = Box::new([0; crate::core::STACK_SIZE]);
+    let cb = Box::new(move || -> isize { unsafe { helper.run() } });
+    let helper = unsafe { clone(cb, &mut *stack, CloneFlags::empty(), Some(libc::SIGCHLD)) }?;
+    drop(helper_sock);
+
+    let (stop_r, stop_w) = rustix::pipe::pipe_with(rustix::pipe::PipeFlags::CLOEXEC)?;
+    listener.set_nonblocking(true)?;
+    let thread = std::thread::spawn(move || accept_loop(listener, sock, stop_r));
+    Ok(Forwarder {
+        helper: helper as libc::pid_t,
+        stop: Some(stop_w),
+        thread: Some(thread),
+    })
+}
+
+fn sockaddr(addr: &std::net::SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        std::net::SocketAddr::V4(a) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: a.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from(*a.ip()).to_be(),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sin) };
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        std::net::SocketAddr::V6(a) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: a.port().to_be(),
+                sin6_flowinfo: 0,
+                sin6_addr: libc::in6_addr {
+                    s6_addr: a.ip().octets(),
+                },
+                sin6_scope_id: 0,
+            };
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Space for the control message of one fd.
+#[repr(C, align(8))]
+struct CmsgBuf([u8; 64]);
+
+/// Connects sockets in the namespaces of the child on request.
+///
+/// It runs after a `clone(2)` of a possibly multithreaded process, so
+/// it only makes raw syscalls, without allocating or panicking.
+struct ConnectHelper {
+    sock: libc::c_int,
+    user: libc::c_int,
+    net: libc::c_int,
+    family: libc::c_int,
+    addr: libc::sockaddr_storage,
+    addr_len: libc::socklen_t,
+}
+
+impl ConnectHelper {
+    /// Serve requests until `sock` is closed.
+    ///
+    /// Each request byte is answered with a status byte, `0` comes with
+    /// a connected socket.
+    unsafe fn run(&self) -> isize {
+        unsafe {
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+            // Nothing of the parent is kept open, like the pipes that hold
+            // the child at its setup barrier.
+            close_all_except(self.sock, &[self.user, self.net]);
+            if self.user >= 0 && libc::setns(self.user, libc::CLONE_NEWUSER) != 0 {
+                return 1;
+            }
+            if libc::setns(self.net, libc::CLONE_NEWNET) != 0 {
+                return 1;
+            }
+            libc::close(self.user);
+            libc::close(self.net);
+            loop {
+                let mut req = 0u8;
+                match libc::read(self.sock, &mut req as *mut u8 as *mut libc::c_void, 1) {
+                    1 => (),
+                    -1 if *libc::__errno_location() == libc::EINTR => continue,
+                    _ => return 0,
+                }
+                let conn = libc::socket(self.family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+                let connected = conn >= 0
+                    && libc::connect(
+                        conn,
+                        &self.addr as *const _ as *const libc::sockaddr,
+                        self.addr_len,
+                    ) == 0;
+                let sent = match connected {
+                    true => self.send_fd(conn),
+                    false => {
+                        libc::write(self.sock, &1u8 as *const u8 as *const libc::c_void, 1) == 1
+                    }
+                };
+                if conn >= 0 {
+                    libc::close(conn);
+                }
+                if !sent {
+                    return 1;
+                }
+            }
+        }
+    }
+
+    unsafe fn send_fd(&self, fd: libc::c_int) -> bool {
+        unsafe {
+            let mut status = 0u8;
+            let mut iov = libc::iovec {
+                iov_base: &mut status as *mut u8 as *mut libc::c_void,
+                iov_len: 1,
+            };
+            let mut buf = CmsgBuf([0; 64]);
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = buf.0.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(std::mem::size_of::<libc::c_int>() as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<libc::c_int>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, fd);
+            libc::sendmsg(self.sock, &msg, libc::MSG_NOSIGNAL) == 1
+        }
+    }
+}
+
+/// Close all fds but `keep` and `also`, with raw syscalls only.
+unsafe fn close_all_except(keep: libc::c_int, also: &[libc::c_int]) {
+    let mut kept = [keep, -1, -1];
+    for (slot, fd) in kept[1..].iter_mut().zip(also) {
+        *slot = *fd;
+    }
+    kept.sort_unstable();
+    let mut first = 0u32;
+    for fd in kept.iter().filter(|fd| **fd >= 0) {
+        let fd = *fd as u32;
+        if fd > first {
+            unsafe { close_range(first, fd - 1) };
+        }
+        first = fd + 1;
+    }
+    unsafe { close_range(first, u32::MAX) };
+}
+
+unsafe fn close_range(first: u32, last: u32) {
+    unsafe {
+        if libc::syscall(libc::SYS_close_range, first, last, 0) == 0 {
+            return;
+        }
+        // Before Linux 5.9.
+        for fd in first..=last.min(4095) {
+            libc::close(fd as libc::c_int);
+        }
+    }
+}
+
+/// Accept connections until `stop` is closed, and forward each of them
+/// to a socket connected by the helper at the other end of `helper`.
+///
+/// Connections still open then are shut down, and their copying
+/// threads joined, so none of them outlives the [`Forwarder`].
+fn accept_loop(listener: TcpListener, helper: OwnedFd, stop: OwnedFd) {
+    let mut conns: Vec<(TcpStream, TcpStream, std::thread::JoinHandle<()>)> = Vec::new();
+    loop {
+        conns.retain(|(_, _, thread)| !thread.is_finished());
+        let mut fds = [
+            libc::pollfd {
+                fd: listener.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stop.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+            continue;
+        }
+        if fds[1].revents != 0 {
+            break;
+        }
+        let conn = match listener.accept() {
+            Ok((conn, _)) => conn,
+            Err(_) => continue,
+        };
+        match connect_upstream(&helper) {
+            Ok(Some(upstream)) => {
+                let (Ok(a), Ok(b)) = (conn.try_clone(), upstream.try_clone()) else {
+                    continue;
+                };
+                let thread = std::thread::spawn(move || splice_streams(conn, upstream));
+                conns.push((a, b, thread));
+            }
+            // The port is not open in the container, refuse.
+            Ok(None) => (),
+            Err(_) => break,
+        }
+    }
+    for (a, b, thread) in conns {
+        let _ = a.shutdown(std::net::Shutdown::Both);
+        let _ = b.shutdown(std::net::Shutdown::Both);
+        let _ = thread.join();
+    }
+}
+
+/// Ask the helper for a connected socket.
+fn connect_upstream(helper: &OwnedFd) -> Result<Option<TcpStream>, Error> {
+    rustix::io::write(helper, &[0])?;
+    let mut status = 1u8;
+    let mut iov = libc::iovec {
+        iov_base: &mut status as *mut u8 as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let mut buf = CmsgBuf([0; 64]);
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = buf.0.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = buf.0.len() as _;
+    let n = loop {
+        let n = unsafe { libc::recvmsg(helper.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if n >= 0 || std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted {
+            break n;
+        }
+    };
+    if n != 1 {
+        return Err(Error::OsErrno(libc::EPIPE));
+    }
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    if status != 0 || cmsg.is_null() {
+        return Ok(None);
+    }
+    let fd = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) };
+    Ok(Some(unsafe { TcpStream::from_raw_fd(fd) }))
+}
+
+/// Copy bytes in both directions until either side is closed.
+fn splice_streams(a: TcpStream, b: TcpStream) {
+    let (Ok(mut a2), Ok(mut b2)) = (a.try_clone(), b.try_clone()) else {
+        return;
+    };
+    let t = std::thread::spawn(move || {
+        let mut a = a;
+        let mut b = b;
+        let _ = std::io::copy(&mut a, &mut b);
+        let _ = b.shutdown(std::net::Shutdown::Write);
+    });
+    let _ = std::io::copy(&mut b2, &mut a2);
+    let _ = a2.shutdown(std::net::Shutdown::Write);
+    let _ = t.join();
+}
@@ -0,0 +1,669 @@
+/*!
+Load OCI runtime bundles.
+
+A bundle is a directory with a `config.json` following the
+[OCI runtime specification](https://github.com/opencontainers/runtime-spec/blob/main/config.md)
+and, usually, the root filesystem. [`Spec`] is the subset of
+`config.json` that nswrap understands, other fields are ignored.
+
+[`Hooks`] run at the points of the container lifecycle defined by the
+specification, see [`Spec::apply_hooks()`] and [`run_hooks()`].
+*/
+use crate::error::Error;
+use crate::{config, Wrap};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+
+/// Spec is the base configuration for the container.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Spec {
+    /// Version of the Open Container Initiative Runtime Specification
+    /// with which the bundle complies.
+    pub oci_version: String,
+    /// Process configures the container process.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process: Option<Process>,
+    /// Root configures the container's root filesystem.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<Root>,
+    /// Hostname configures the container's hostname.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Mounts configures additional mounts (on top of Root).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mounts: Vec<Mount>,
+    /// Annotations contains arbitrary metadata for the container.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+    /// Hooks configures callbacks for container lifecycle events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hooks: Option<Hooks>,
+    /// Linux is platform-specific configuration for Linux based containers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linux: Option<Linux>,
+}
+
+/// State holds information about the runtime state of the container.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    /// Version is the version of the specification that is supported.
+    pub oci_version: String,
+    /// ID is the container ID.
+    pub id: String,
+    /// Status is the runtime status of the container.
+    pub status: Status,
+    /// Pid is the process ID for the container process.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<i32>,
+    /// Bundle is the path to the container's bundle directory.
+    pub bundle: PathBuf,
+    /// Annotations are key values associated with the container.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+}
+
+/// ContainerState represents the state of a container.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// The container is being created.
+    Creating,
+    /// The runtime has finished the create operation.
+    Created,
+    /// The container process has executed the user-specified program but
+    /// has not exited.
+    Running,
+    /// The container process has exited.
+    Stopped,
+}
+
+/// Hooks specifies a command that is run in the container at a
+/// particular event in the lifecycle of a container.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Hooks {
+    /// Prestart is Deprecated. Prestart is a list of hooks to be run
+    /// before the container process is executed. It is called in the
+    /// Runtime Namespace.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prestart: Vec<Hook>,
+    /// CreateRuntime is a list of hooks to be run after the container
+    /// has been created but before pivot_root or any equivalent operation
+    /// has been called. It is called in the Runtime Namespace.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub create_runtime: Vec<Hook>,
+    /// CreateContainer is a list of hooks to be run after the container
+    /// has been created but before pivot_root or any equivalent operation
+    /// has been called. It is called in the Container Namespace.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub create_container: Vec<Hook>,
+    /// StartContainer is a list of hooks to be run after the start
+    /// operation is called but before the container process is started.
+    /// It is called in the Container Namespace.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub start_container: Vec<Hook>,
+    /// Poststart is a list of hooks to be run after the container process
+    /// is started. It is called in the Runtime Namespace.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub poststart: Vec<Hook>,
+    /// Poststop is a list of hooks to be run after the container process
+    /// exits. It is called in the Runtime Namespace.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub poststop: Vec<Hook>,
+}
+
+/// Hook specifies a command that is run at a particular event in the
+/// lifecycle of a container.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Hook {
+    /// Path to the executable, absolute in the namespace it is run in.
+    pub path: PathBuf,
+    /// Arguments, including the executable name.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Environment, in `KEY=value` form.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<String>,
+    /// Seconds after which the hook is aborted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u32>,
+}
+
+/// Process contains information to start a specific application inside the
+/// container.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Process {
+    /// Terminal creates an interactive terminal for the container.
+    #[serde(default)]
+    pub terminal: bool,
+    /// User specifies user information for the process.
+    #[serde(default)]
+    pub user: User,
+    /// Args specifies the binary and arguments for the application to
+    /// execute.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Env populates the process environment for the process.
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// Cwd is the current working directory for the process and must be
+    /// relative to the container's root.
+    pub cwd: PathBuf,
+}
+
+/// User specifies specific user (and group) information for the container
+/// process.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    /// UID is the user id.
+    pub uid: u32,
+    /// GID is the group id.
+    pub gid: u32,
+}
+
+/// Root contains information about the container's root filesystem on the
+/// host.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Root {
+    /// Path is the absolute path to the container's root filesystem.
+    pub path: PathBuf,
+    /// Readonly makes the root filesystem for the container readonly before
+    /// the process is executed.
+    #[serde(default)]
+    pub readonly: bool,
+}
+
+/// Mount specifies a mount for a container.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Mount {
+    /// Destination is the absolute path where the mount will be placed in
+    /// the container.
+    pub destination: PathBuf,
+    /// Type specifies the mount kind.
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+    /// Source specifies the source path of the mount.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<PathBuf>,
+    /// Options are fstab style mount options.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<Vec<String>>,
+    /// UID mappings for ID-mapped mounts.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub uid_mappings: Vec<IdMapping>,
+    /// GID mappings for ID-mapped mounts.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gid_mappings: Vec<IdMapping>,
+}
+
+/// LinuxIDMapping specifies UID/GID mappings.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IdMapping {
+    /// ContainerID is the starting UID/GID in the container.
+    #[serde(rename = "containerID")]
+    pub container_id: u32,
+    /// HostID is the starting UID/GID on the host to be mapped to
+    /// `container_id`.
+    #[serde(rename = "hostID")]
+    pub host_id: u32,
+    /// Size is the number of IDs to be mapped.
+    pub size: u32,
+}
+
+/// Linux contains platform-specific configuration for Linux based
+/// containers.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Linux {
+    /// UIDMapping specifies user mappings for supporting user namespaces.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub uid_mappings: Vec<IdMapping>,
+    /// GIDMapping specifies group mappings for supporting user namespaces.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gid_mappings: Vec<IdMapping>,
+    /// Namespaces contains the namespaces that are created and/or joined by
+    /// the container.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub namespaces: Vec<Namespace>,
+    /// MaskedPaths masks over the provided paths inside the container.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub masked_paths: Vec<PathBuf>,
+    /// ReadonlyPaths sets the provided paths as RO inside the container.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub readonly_paths: Vec<PathBuf>,
+}
+
+/// LinuxNamespace is the configuration for a Linux namespace.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Namespace {
+    /// Type is the type of namespace.
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// Path is a path to an existing namespace persisted on disk that can
+    /// be joined and is of the same type.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+/// Features of the runtime, the content of `features.json`, see the
+/// [features document](https://github.com/opencontainers/runtime-spec/blob/main/features.md).
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Features {
+    /// Minimum version of the specification that is supported.
+    pub oci_version_min: String,
+    /// Maximum version of the specification that is supported.
+    pub oci_version_max: String,
+    /// Hooks that are recognized.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<String>,
+    /// Mount options that are recognized, others are passed to the
+    /// filesystem.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mount_options: Vec<String>,
+    /// Linux specific features.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linux: Option<LinuxFeatures>,
+    /// Implementation specific features, like the features of the
+    /// kernel found by [`crate::util::probe::probe()`].
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+}
+
+/// Linux specific features of the runtime.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LinuxFeatures {
+    /// Namespaces that are recognized and exist in the kernel.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub namespaces: Vec<String>,
+    /// Cgroup managers that are supported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cgroup: Option<CgroupFeatures>,
+    /// Seccomp support.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seccomp: Option<SeccompFeatures>,
+    /// Mount features beyond the options.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mount_extensions: Option<MountExtensions>,
+}
+
+/// Cgroup features of the runtime.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CgroupFeatures {
+    pub v1: bool,
+    pub v2: bool,
+    pub systemd: bool,
+    pub systemd_user: bool,
+}
+
+/// Seccomp features of the runtime.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SeccompFeatures {
+    pub enabled: bool,
+}
+
+/// Mount features beyond the options.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MountExtensions {
+    /// Idmapped mounts, the `uidMappings` and `gidMappings` of a mount.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idmap: Option<Enabled>,
+}
+
+/// A feature that can be enabled.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
+pub struct Enabled {
+    pub enabled: bool,
+}
+
+impl Spec {
+    /// Read `config.json` of the bundle at `bundle`.
+    pub fn load<P: AsRef<Path>>(bundle: P) -> Result<Self, Error> {
+        let path = bundle.as_ref().join("config.json");
+        let file = std::fs::File::open(&path)?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|e| Error::InvalidConfig(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Configure `wrap` to run this spec.
+    ///
+    /// Relative paths of the root and of bind mount sources are resolved
+    /// against `bundle`. Namespaces with a path are opened here, and kept
+    /// open by `wrap`.
+    pub fn apply(&self, wrap: &mut Wrap, bundle: &Path) -> Result<(), Error> {
+        let bundle = std::path::absolute(bundle)?;
+        if let Some(linux) = &self.linux {
+            for ns in &linux.namespaces {
+                let typ = namespace_type(&ns.typ)?;
+                match &ns.path {
+                    Some(path) => {
+                        wrap.nsenter_fd(typ, std::fs::File::open(path)?.into());
+                    }
+                    None => {
+                        wrap.unshare(typ);
+                    }
+                }
+            }
+            for m in &linux.uid_mappings {
+                wrap.uid_map(m.host_id, m.container_id, m.size);
+            }
+            for m in &linux.gid_mappings {
+                wrap.gid_map(m.host_id, m.container_id, m.size);
+            }
+        }
+
+        if let Some(root) = &self.root {
+            wrap.root_dir(bundle.join(&root.path), root.readonly);
+        }
+        for mnt in &self.mounts {
+            wrap.mount(mnt.to_config(&bundle));
+        }
+        if let Some(linux) = &self.linux {
+            for path in &linux.masked_paths {
+                wrap.mask_path(path);
+            }
+            for path in &linux.readonly_paths {
+                wrap.readonly_path(path);
+            }
+        }
+        if let Some(hostname) = &self.hostname {
+            wrap.hostname(hostname);
+        }
+
+        if let Some(process) = &self.process {
+            let (bin, args) = process
+                .args
+                .split_first()
+                .ok_or_else(|| Error::InvalidConfig("process.args is empty".into()))?;
+            wrap.program(bin).args(args).env_clear();
+            for var in &process.env {
+                match var.split_once('=') {
+                    Some((key, val)) => wrap.env(key, val),
+                    None => wrap.env(var, ""),
+                };
+            }
+            wrap.current_dir(&process.cwd);
+            wrap.process.user = Some(config::User {
+                uid: process.user.uid,
+                gid: process.user.gid,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Spec {
+    /// Run the hooks of this spec for the container described by `state`.
+    ///
+    /// `prestart` and `createRuntime` hooks run in this process, once the
+    /// child entered its namespaces. `createContainer` hooks run in the
+    /// child before its root is changed, and `startContainer` hooks after
+    /// the callbacks that were added before this call. The state passed
+    /// to all of them has the pid of the child and the `creating` status.
+    ///
+    /// Failures of the hooks before `startContainer` are returned by
+    /// [`Wrap::spawn()`]. The setup is reported by then, so a failed
+    /// `startContainer` hook makes the child exit with
+    /// [`START_CONTAINER_FAILED`] instead of running the program.
+    ///
+    /// `poststart` and `poststop` are left to the caller, which knows when
+    /// the container is started and deleted, see [`run_hooks()`].
+    pub fn apply_hooks(&self, wrap: &mut Wrap, state: &State) -> Result<(), Error> {
+        let hooks = match &self.hooks {
+            Some(hooks) => hooks.clone(),
+            None => return Ok(()),
+        };
+        let mut state = state.clone();
+        state.status = Status::Creating;
+
+        // The pid is only known here once the child is cloned, the child
+        // reads the state from this pipe, once for each of its stages.
+        let in_child = [&hooks.create_container, &hooks.start_container]
+            .iter()
+            .filter(|hooks| !hooks.is_empty())
+            .count();
+        let (state_r, state_w) = rustix::pipe::pipe_with(rustix::pipe::PipeFlags::CLOEXEC)?;
+        let state_r = std::sync::Arc::new(state_r);
+        wrap.parent_callback(move |pid| {
+            state.pid = Some(pid as i32);
+            let mut line = serde_json::to_vec(&state).map_err(|e| hook_error("state", e))?;
+            line.push(b'\n');
+            for _ in 0..in_child {
+                std::fs::File::from(state_w.try_clone()?).write_all(&line)?;
+            }
+            run_hooks(&hooks.prestart, &state)?;
+            run_hooks(&hooks.create_runtime, &state)
+        });
+
+        if !hooks.create_container.is_empty() {
+            let state_r = state_r.clone();
+            wrap.setup_callback(move || {
+                run_hooks(&hooks.create_container, &read_state(&state_r)?)
+                    .map_err(|e| e.at("createContainer"))
+            });
+        }
+        if !hooks.start_container.is_empty() {
+            wrap.callback(move || {
+                let res = read_state(&state_r)
+                    .and_then(|state| run_hooks(&hooks.start_container, &state));
+                if res.is_err() {
+                    unsafe { libc::_exit(START_CONTAINER_FAILED) };
+                }
+                0
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Run `hooks` in order, until one of them fails.
+pub fn run_hooks(hooks: &[Hook], state: &State) -> Result<(), Error> {
+    hooks.iter().try_for_each(|hook| hook.run(state))
+}
+
+impl Hook {
+    /// Run the hook with `state` on its stdin, and wait for it to exit
+    /// successfully.
+    pub fn run(&self, state: &State) -> Result<(), Error> {
+        use std::os::unix::process::CommandExt;
+        use std::process::{Command, Stdio};
+
+        let name = self.path.display().to_string();
+        let mut cmd = Command::new(&self.path);
+        if let Some((arg0, args)) = self.args.split_first() {
+            cmd.arg0(arg0).args(args);
+        }
+        cmd.env_clear().stdin(Stdio::piped());
+        for var in &self.env {
+            match var.split_once('=') {
+                Some((key, val)) => cmd.env(key, val),
+                None => cmd.env(var, ""),
+            };
+        }
+        let mut child = cmd.spawn().map_err(|e| hook_error(&name, e))?;
+
+        let json = serde_json::to_vec(state).map_err(|e| hook_error(&name, e))?;
+        // A hook that does not read its stdin is fine.
+        let _ = child.stdin.take().map(|mut stdin| stdin.write_all(&json));
+
+        let deadline = self
+            .timeout
+            .map(|secs| std::time::Instant::now() + std::time::Duration::from_secs(secs.into()));
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(hook_error(&name, "timed out"));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        };
+        match status.success() {
+            true => Ok(()),
+            false => Err(hook_error(&name, status)),
+        }
+    }
+}
+
+fn hook_error<E: std::fmt::Display>(name: &str, e: E) -> Error {
+    Error::HookFailed(format!("{}: {}", name, e))
+}
+
+/// Read one line of state written by [`Spec::apply_hooks()`].
+fn read_state(fd: &OwnedFd) -> Result<State, Error> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    // One byte at a time, the rest is for the next stage.
+    while rustix::io::read(fd, &mut byte)? == 1 && byte[0] != b'\n' {
+        line.push(byte[0]);
+    }
+    serde_json::from_slice(&line).map_err(|e| hook_error("state", e))
+}
+
+impl Mount {
+    fn to_config(&self, bundle: &Path) -> config::Mount {
+        let bind_option = self
+            .options
+            .iter()
+            .flatten()
+            .any(|o| o == "bind" || o == "rbind");
+        let is_bind = bind_option || self.typ.as_deref() == Some("bind");
+        let source = match (&self.source, is_bind) {
+            (Some(source), true) => Some(bundle.join(source)),
+            (source, _) => source.clone(),
+        };
+        let mut options = self.options.clone();
+        if is_bind && !bind_option {
+            options.get_or_insert_with(Vec::new).push("bind".into());
+        }
+        let map = |m: &IdMapping| config::IdMap {
+            host_id: m.host_id,
+            container_id: m.container_id,
+            size: m.size,
+        };
+        config::Mount {
+            destination: self.destination.clone(),
+            // Bind mounts have no filesystem type, but it is often
+            // given as "bind" or "none".
+            typ: self.typ.clone().filter(|_| !is_bind),
+            source,
+            options,
+            uid_mappings: self.uid_mappings.iter().map(map).collect(),
+            gid_mappings: self.gid_mappings.iter().map(map).collect(),
+        }
+    }
+}
+
+/// Exit status of a child whose `startContainer` hook failed, see
+/// [`Spec::apply_hooks()`].
+pub const START_CONTAINER_FAILED: i32 = 126;
+
+/// Prefix of the annotations of [`features()`].
+const ANNOTATION_PREFIX: &str = "io.github.imbearchild.nswrap";
+
+/// Describe what nswrap supports on this host, for `features.json`.
+///
+/// Cgroups and seccomp are not managed by nswrap. The kernel features
+/// found by [`crate::util::probe::probe()`] that the specification has
+/// no field for are annotations, like
+/// `io.github.imbearchild.nswrap.landlock.abi`.
+pub fn features() -> Features {
+    let probe = crate::util::probe::probe();
+    let mut annotations = HashMap::new();
+    let mut annotate = |key: &str, value: String| {
+        annotations.insert(format!("{}.{}", ANNOTATION_PREFIX, key), value);
+    };
+    let userns = &probe.user_namespaces;
+    annotate("userns.allowed", userns.allowed.to_string());
+    annotate("clone3", probe.clone3.to_string());
+    annotate("pidfd", probe.pidfd.to_string());
+    annotate("mount.api", probe.mount_api.to_string());
+    if let Some(abi) = probe.landlock_abi {
+        annotate("landlock.abi", abi.to_string());
+    }
+    annotate("seccomp.user_notif", probe.seccomp.user_notif.to_string());
+    annotate("cgroup.v2", probe.cgroup.v2.to_string());
+    annotate("cgroup.delegated", probe.cgroup.delegated.to_string());
+
+    Features {
+        oci_version_min: "1.0.0".into(),
+        oci_version_max: "1.1.0".into(),
+        hooks: [
+            "prestart",
+            "createRuntime",
+            "createContainer",
+            "startContainer",
+            "poststart",
+            "poststop",
+        ]
+        .map(String::from)
+        .into(),
+        mount_options: crate::mount::MountOptions::known()
+            .map(String::from)
+            .collect(),
+        linux: Some(LinuxFeatures {
+            namespaces: probe
+                .namespaces
+                .iter()
+                .filter(|ns| namespace_type(ns).is_ok())
+                .map(|ns| ns.to_string())
+                .collect(),
+            cgroup: Some(CgroupFeatures::default()),
+            seccomp: Some(SeccompFeatures::default()),
+            mount_extensions: Some(MountExtensions {
+                idmap: Some(Enabled {
+                    enabled: probe.idmapped_mounts,
+                }),
+            }),
+        }),
+        annotations,
+    }
+}
+
+fn namespace_type(typ: &str) -> Result<config::NamespaceType, Error> {
+    Ok(match typ {
+        "pid" => config::NamespaceType::Pid,
+        "network" => config::NamespaceType::Network,
+        "mount" => config::NamespaceType::Mount,
+        "ipc" => config::NamespaceType::Ipc,
+        "uts" => config::NamespaceType::Uts,
+        "user" => config::NamespaceType::User,
+        "cgroup" => config::NamespaceType::Cgroup,
+        typ => {
+            return Err(Error::InvalidConfig(format!(
+                "unsupported namespace {}",
+                typ
+            )))
+        }
+    })
+}
+
+impl Wrap<'_> {
+    /// Create a `Wrap` that runs the OCI bundle at `bundle`.
+    ///
+    /// See [`Spec::apply()`] for how the bundle is configured.
+    pub fn from_oci_bundle<P: AsRef<Path>>(bundle: P) -> Result<Self, Error> {
+        let spec = Spec::load(&bundle)?;
+        let mut wrap = Wrap::new();
+        spec.apply(&mut wrap, bundle.as_ref())?;
+        Ok(wrap)
+    }
+}
@@ -0,0 +1,329 @@
+/*!
+Declarative sandbox profiles.
+
+A profile is a TOML file describing what a [`Wrap`] does, so that
+vetted sandbox policies can be shared without writing builder code:
+
+```toml
+extends = "base"
+namespaces = ["user", "mount", "pid", "network"]
+id_map_preset = "current"
+hostname = "build"
+network = "loopback_only"
+default_masks = true
+readonly_paths = ["/sys"]
+
+[process]
+program = "/usr/bin/make"
+args = ["-j4"]
+env_clear = true
+env = { PATH = "/usr/bin:/bin" }
+
+[[mounts]]
+destination = "/src"
+source = "/home/user/src"
+options = ["rbind", "ro"]
+```
+
+Profiles are looked up by name as `nswrap/profiles/<name>.toml` in the
+XDG config dirs, `~/.config/nswrap/profiles/` first. Names with a `/`
+are paths, relative to the profile that extends them.
+
+A profile that `extends` another one overrides its single values, and
+adds to its lists: namespaces, mounts, masked and readonly paths, and
+the environment. Id maps, the arguments and the root, one of `root_dir`,
+`overlay` and `sandbox_mnt`, are replaced as a whole.
+*/
+use crate::error::Error;
+use crate::{config, Wrap};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// A sandbox profile, see the [module documentation](self).
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Name or path of the profile this one is based on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    /// Namespaces to unshare.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub namespaces: Vec<config::NamespaceType>,
+    /// Id maps of the user namespace, see [`Wrap::id_map_preset()`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_map_preset: Option<config::IdMapPreset>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub uid_maps: Vec<config::IdMap>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub gid_maps: Vec<config::IdMap>,
+    pub process: ProfileProcess,
+    /// Host directory used as root, see [`Wrap::root_dir()`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root_dir: Option<config::Root>,
+    /// Overlay used as root, see [`Wrap::overlay_root()`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overlay: Option<config::Overlay>,
+    /// Tmpfs used as root, see [`Wrap::sandbox_mnt_config()`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sandbox_mnt: Option<config::SandboxMnt>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<config::NetworkConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etc_files: Option<config::EtcFiles>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proc: Option<config::ProcOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dev: Option<config::DevConfig>,
+    /// Mask the paths hidden by runc, see [`Wrap::default_masks()`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_masks: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub masked_paths: Vec<PathBuf>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub readonly_paths: Vec<PathBuf>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mounts: Vec<config::Mount>,
+}
+
+/// The program run by a profile and its environment.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileProcess {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// Do not inherit the environment of the parent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env_clear: Option<bool>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub env_remove: Vec<String>,
+}
+
+impl Profile {
+    /// Parse a profile, without resolving what it extends.
+    pub fn from_toml(s: &str) -> Result<Self, Error> {
+        toml::from_str(s).map_err(|e| Error::InvalidConfig(e.to_string()))
+    }
+
+    /// Load the profile `name` and the profiles it extends, see the
+    /// [module documentation](self) for how it is found.
+    pub fn load(name: &str) -> Result<Self, Error> {
+        Self::load_in(name, &config_dirs())
+    }
+
+    /// Like [`Self::load()`], with profiles looked up by name as
+    /// `<name>.toml` in `dirs`, in order.
+    pub fn load_in<P: AsRef<Path>>(name: &str, dirs: &[P]) -> Result<Self, Error> {
+        let dirs: Vec<_> = dirs.iter().map(|dir| dir.as_ref()).collect();
+        Self::load_from(name, None, &dirs, &mut Vec::new())
+    }
+
+    /// Load the profile at `path` and the profiles it extends.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let dirs = config_dirs();
+        let dirs: Vec<_> = dirs.iter().map(|dir| dir.as_path()).collect();
+        Self::load_path(path.as_ref(), &dirs, &mut Vec::new())
+    }
+
+    fn load_from(
+        name: &str,
+        base: Option<&Path>,
+        dirs: &[&Path],
+        seen: &mut Vec<PathBuf>,
+    ) -> Result<Self, Error> {
+        let path = match name.contains('/') {
+            true => base.unwrap_or(Path::new("")).join(name),
+            false => dirs
+                .iter()
+                .map(|dir| dir.join(format!("{}.toml", name)))
+                .find(|path| path.is_file())
+                .ok_or_else(|| Error::InvalidConfig(format!("profile {} not found", name)))?,
+        };
+        Self::load_path(&path, dirs, seen)
+    }
+
+    fn load_path(path: &Path, dirs: &[&Path], seen: &mut Vec<PathBuf>) -> Result<Self, Error> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            Error::InvalidConfig(format!("profile {}: {}", path.display(), e))
+        };
+        let canonical = path.canonicalize().map_err(|e| invalid(&e))?;
+        if seen.contains(&canonical) {
+            return Err(invalid(&"extends itself"));
+        }
+        seen.push(canonical);
+
+        let s = std::fs::read_to_string(path).map_err(|e| invalid(&e))?;
+        let profile = Self::from_toml(&s).map_err(|e| invalid(&e))?;
+        match &profile.extends {
+            Some(name) => {
+                let base = Self::load_from(name, path.parent(), dirs, seen)?;
+                Ok(base.merge(profile))
+            }
+            None => Ok(profile),
+        }
+    }
+
+    /// Apply `other` on top of this profile.
+    fn merge(mut self, other: Profile) -> Profile {
+        for ns in other.namespaces {
+            if !self
+                .namespaces
+                .iter()
+                .any(|n| std::mem::discriminant(n) == std::mem::discriminant(&ns))
+            {
+                self.namespaces.push(ns);
+            }
+        }
+        if other.id_map_preset.is_some()
+            || !(other.uid_maps.is_empty() && other.gid_maps.is_empty())
+        {
+            self.id_map_preset = other.id_map_preset;
+            self.uid_maps = other.uid_maps;
+            self.gid_maps = other.gid_maps;
+        }
+        self.process.program = other.process.program.or(self.process.program);
+        self.process.args = other.process.args.or(self.process.args);
+        self.process.cwd = other.process.cwd.or(self.process.cwd);
+        self.process.env_clear = other.process.env_clear.or(self.process.env_clear);
+        for key in &other.process.env_remove {
+            self.process.env.remove(key);
+        }
+        self.process.env.extend(other.process.env);
+        self.process.env_remove.extend(other.process.env_remove);
+        if other.root_dir.is_some() || other.overlay.is_some() || other.sandbox_mnt.is_some() {
+            self.root_dir = other.root_dir;
+            self.overlay = other.overlay;
+            self.sandbox_mnt = other.sandbox_mnt;
+        }
+        self.hostname = other.hostname.or(self.hostname);
+        self.network = other.network.or(self.network);
+        self.etc_files = other.etc_files.or(self.etc_files);
+        self.proc = other.proc.or(self.proc);
+        self.dev = other.dev.or(self.dev);
+        self.default_masks = other.default_masks.or(self.default_masks);
+        self.masked_paths.extend(other.masked_paths);
+        self.readonly_paths.extend(other.readonly_paths);
+        self.mounts.extend(other.mounts);
+        self.extends = None;
+        self
+    }
+
+    /// Configure `wrap` as described by this profile.
+    pub fn apply(&self, wrap: &mut Wrap) -> Result<(), Error> {
+        for ns in &self.namespaces {
+            wrap.unshare(*ns);
+        }
+        match self.id_map_preset {
+            Some(config::IdMapPreset::Auto) => {
+                return Err(Error::InvalidConfig(
+                    "id_map_preset \"auto\" is not supported".into(),
+                ))
+            }
+            Some(preset) => {
+                wrap.id_map_preset(preset);
+            }
+            None => (),
+        }
+        for m in &self.uid_maps {
+            wrap.uid_map(m.host_id, m.container_id, m.size);
+        }
+        for m in &self.gid_maps {
+            wrap.gid_map(m.host_id, m.container_id, m.size);
+        }
+
+        let process = &self.process;
+        if let Some(program) = &process.program {
+            wrap.program(program);
+        }
+        if let Some(args) = &process.args {
+            wrap.args(args);
+        }
+        if let Some(cwd) = &process.cwd {
+            wrap.current_dir(cwd);
+        }
+        if process.env_clear == Some(true) {
+            wrap.env_clear();
+        }
+        for key in &process.env_remove {
+            wrap.env_remove(key);
+        }
+        wrap.envs(&process.env);
+
+        let roots = [
+            self.root_dir.is_some(),
+            self.overlay.is_some(),
+            self.sandbox_mnt.is_some(),
+        ];
+        if roots.iter().filter(|set| **set).count() > 1 {
+            return Err(Error::InvalidConfig(
+                "only one of root_dir, overlay and sandbox_mnt can be set".into(),
+            ));
+        }
+        if let Some(overlay) = &self.overlay {
+            wrap.overlay_root(&overlay.lower, overlay.upper.clone());
+        } else if let Some(root) = &self.root_dir {
+            wrap.root_dir(&root.path, root.readonly.unwrap_or(false));
+        } else if let Some(sandbox) = &self.sandbox_mnt {
+            wrap.sandbox_mnt_config(sandbox.clone());
+        }
+        if let Some(hostname) = &self.hostname {
+            wrap.hostname(hostname);
+        }
+        if let Some(network) = &self.network {
+            wrap.network(network.clone());
+        }
+        if let Some(etc_files) = &self.etc_files {
+            wrap.etc_files(etc_files.clone());
+        }
+        if let Some(proc) = &self.proc {
+            wrap.proc_mount(proc.clone());
+        }
+        if let Some(dev) = &self.dev {
+            wrap.dev(dev.clone());
+        }
+        for mnt in &self.mounts {
+            wrap.mount(mnt.clone());
+        }
+        if self.default_masks == Some(true) {
+            wrap.default_masks();
+        }
+        for path in &self.masked_paths {
+            wrap.mask_path(path);
+        }
+        for path in &self.readonly_paths {
+            wrap.readonly_path(path);
+        }
+        Ok(())
+    }
+}
+
+/// Profile dirs in the XDG config dirs, `~/.config/nswrap/profiles` first.
+fn config_dirs() -> Vec<PathBuf> {
+    match xdg::BaseDirectories::with_prefix("nswrap") {
+        Ok(xdg) => std::iter::once(xdg.get_config_home())
+            .chain(xdg.get_config_dirs())
+            .map(|dir| dir.join("profiles"))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+impl Wrap<'_> {
+    /// Create a `Wrap` from the profile `name`.
+    ///
+    /// See the [`profile`](crate::profile) module for the format and
+    /// where profiles are looked up.
+    pub fn from_profile(name: &str) -> Result<Self, Error> {
+        let mut wrap = Wrap::new();
+        Profile::load(name)?.apply(&mut wrap)?;
+        Ok(wrap)
+    }
+}
@@ -8,6 +8,10 @@ use linux_raw_sys::general::{
 };
 use std::os::fd::RawFd;
 
+pub mod probe;
+
+pub use linux_raw_sys::general::mount_attr;
+
 pub fn get_uid() -> u32 {
     rustix::process::getuid().as_raw()
 }
@@ -56,7 +60,7 @@ pub fn unshare(flags: CloneFlags) -> Result<(), Error> {
     let res = unsafe { libc::unshare(flags.bits() as i32) };
 
     if res == -1 {
-        Err(Error::OsErrno(unsafe { *libc::__errno_location().clone() }))
+        Err(Error::OsErrno(unsafe { *libc::__errno_location() }))
     } else {
         Ok(())
     }
@@ -69,7 +73,45 @@ pub fn setns(fd: RawFd, nstype: CloneFlags) -> Result<(), Error> {
     let res = unsafe { libc::setns(fd, nstype.bits() as i32) };
 
     if res == -1 {
-        Err(Error::OsErrno(unsafe { *libc::__errno_location().clone() }))
+        Err(Error::OsErrno(unsafe { *libc::__errno_location() }))
+    } else {
+        Ok(())
+    }
+}
+
+/// set hostname of the current UTS namespace
+///
+/// See also [sethostname(2)](https://man7.org/linux/man-pages/man2/sethostname.2.html)
+pub fn sethostname(name: &str) -> Result<(), Error> {
+    let res = unsafe { libc::sethostname(name.as_ptr() as *const libc::c_char, name.len()) };
+
+    if res == -1 {
+        Err(Error::OsErrno(unsafe { *libc::__errno_location() }))
+    } else {
+        Ok(())
+    }
+}
+
+/// change properties of a mount or mount tree
+///
+/// `fd` is a mount from `open_tree(2)` or `fsmount(2)`, `flags` are the
+/// `AT_*` flags, usually `AT_EMPTY_PATH` with an optional `AT_RECURSIVE`.
+///
+/// See also [mount_setattr(2)](https://man7.org/linux/man-pages/man2/mount_setattr.2.html)
+pub fn mount_setattr(fd: RawFd, flags: u32, attr: &mount_attr) -> Result<(), Error> {
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            fd,
+            c"".as_ptr(),
+            flags,
+            attr as *const mount_attr,
+            std::mem::size_of::<mount_attr>(),
+        )
+    };
+
+    if res == -1 {
+        Err(Error::OsErrno(unsafe { *libc::__errno_location() }))
     } else {
         Ok(())
     }
@@ -86,6 +128,12 @@ pub type CloneCb<'a> = Box<dyn FnMut() -> isize + 'a>;
 /// address need not be the highest address of the region.  Nix will take
 /// care of that requirement.  The user only needs to provide a reference to
 /// a normally allocated buffer.
+///
+/// # Safety
+///
+/// The child shares nothing but a copy of the parent's memory, so `cb`
+/// must only do things that are sound after a `clone(2)` without
+/// `CLONE_VM`, see [`crate::Wrap::callback`].
 pub unsafe fn clone(
     mut cb: CloneCb,
     stack: &mut [u8],
@@ -102,7 +150,10 @@ pub unsafe fn clone(
         let ptr = stack.as_mut_ptr().add(stack.len());
         let ptr_aligned = ptr.sub(ptr as usize % 16);
         libc::clone(
-            std::mem::transmute(callback as extern "C" fn(*mut Box<dyn FnMut() -> isize>) -> i32),
+            std::mem::transmute::<
+                extern "C" fn(*mut Box<dyn FnMut() -> isize>) -> i32,
+                extern "C" fn(*mut libc::c_void) -> i32,
+            >(callback as extern "C" fn(*mut Box<dyn FnMut() -> isize>) -> i32),
             ptr_aligned as *mut libc::c_void,
             combined,
             &mut cb as *mut _ as *mut libc::c_void,
@@ -110,7 +161,7 @@ pub unsafe fn clone(
     };
 
     if res == -1 {
-        Err(Error::OsErrno(unsafe { *libc::__errno_location().clone() }))
+        Err(Error::OsErrno(unsafe { *libc::__errno_location() }))
     } else {
         Ok(res as u32)
     }
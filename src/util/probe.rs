@@ -0,0 +1,271 @@
+/*!
+Detect what the running kernel allows, to pick a sandbox strategy.
+
+[`probe()`] does not change the calling process: syscalls are called
+with arguments they reject before doing anything, and the user
+namespace is tried in a forked child. With the `serde` feature,
+`oci::features()` turns the report into an OCI `features.json`.
+*/
+use libc::{c_long, c_uint};
+use std::path::{Path, PathBuf};
+
+/// What the kernel allows, see [`probe()`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Features {
+    /// Namespaces the kernel has, with their OCI names, like `"mount"`
+    /// or `"time"`.
+    pub namespaces: Vec<&'static str>,
+    pub user_namespaces: UserNamespaces,
+    /// `clone3(2)`, Linux 5.3.
+    pub clone3: bool,
+    /// `pidfd_open(2)`, Linux 5.3.
+    pub pidfd: bool,
+    /// The mount API of `fsopen(2)` and `open_tree(2)`, Linux 5.2.
+    pub mount_api: bool,
+    /// `mount_setattr(2)` with `MOUNT_ATTR_IDMAP`, Linux 5.12.
+    /// Filesystems still have to support idmapped mounts.
+    pub idmapped_mounts: bool,
+    /// Landlock ABI version, `None` if Landlock is missing or disabled.
+    pub landlock_abi: Option<u32>,
+    pub seccomp: Seccomp,
+    pub cgroup: Cgroup,
+}
+
+/// Whether a user namespace can be created without privileges.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct UserNamespaces {
+    /// A forked child could unshare a user namespace.
+    pub allowed: bool,
+    /// `kernel.unprivileged_userns_clone`, a Debian and Arch sysctl.
+    pub unprivileged_userns_clone: Option<bool>,
+    /// `user.max_user_namespaces`, 0 disables user namespaces.
+    pub max_user_namespaces: Option<u64>,
+    /// `kernel.apparmor_restrict_unprivileged_userns`, set by Ubuntu.
+    pub apparmor_restricted: Option<bool>,
+}
+
+/// Seccomp filter features, with the names of `libseccomp` and the OCI
+/// specification.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Seccomp {
+    /// `SECCOMP_RET_USER_NOTIF` is available, Linux 5.0.
+    pub user_notif: bool,
+    /// Available actions, like `"SCMP_ACT_USER_NOTIF"`.
+    pub actions: Vec<&'static str>,
+    /// Supported filter flags, like `"SECCOMP_FILTER_FLAG_NEW_LISTENER"`.
+    pub flags: Vec<&'static str>,
+}
+
+/// The cgroup of the calling process.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Cgroup {
+    /// `/sys/fs/cgroup` is a cgroup v2 hierarchy.
+    pub v2: bool,
+    /// Path of the cgroup in the v2 hierarchy.
+    pub path: Option<PathBuf>,
+    /// The cgroup can be managed by the caller, like a cgroup delegated
+    /// by systemd.
+    pub delegated: bool,
+    /// Controllers available in the cgroup.
+    pub controllers: Vec<String>,
+}
+
+const NAMESPACES: [(&str, &str); 8] = [
+    ("cgroup", "cgroup"),
+    ("ipc", "ipc"),
+    ("mount", "mnt"),
+    ("network", "net"),
+    ("pid", "pid"),
+    ("time", "time"),
+    ("user", "user"),
+    ("uts", "uts"),
+];
+
+const SECCOMP_SET_MODE_FILTER: c_uint = 1;
+const SECCOMP_GET_ACTION_AVAIL: c_uint = 2;
+const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+
+const SECCOMP_ACTIONS: [(&str, u32); 9] = [
+    ("SCMP_ACT_KILL_PROCESS", 0x8000_0000),
+    ("SCMP_ACT_KILL_THREAD", 0),
+    ("SCMP_ACT_KILL", 0),
+    ("SCMP_ACT_TRAP", 0x0003_0000),
+    ("SCMP_ACT_ERRNO", 0x0005_0000),
+    ("SCMP_ACT_USER_NOTIF", SECCOMP_RET_USER_NOTIF),
+    ("SCMP_ACT_TRACE", 0x7ff0_0000),
+    ("SCMP_ACT_LOG", 0x7ffc_0000),
+    ("SCMP_ACT_ALLOW", 0x7fff_0000),
+];
+
+/// Filter flags, each tried together with the flags it requires.
+const SECCOMP_FLAGS: [(&str, c_uint); 6] = [
+    ("SECCOMP_FILTER_FLAG_TSYNC", 1),
+    ("SECCOMP_FILTER_FLAG_LOG", 2),
+    ("SECCOMP_FILTER_FLAG_SPEC_ALLOW", 4),
+    ("SECCOMP_FILTER_FLAG_NEW_LISTENER", 8),
+    ("SECCOMP_FILTER_FLAG_TSYNC_ESRCH", 16),
+    ("SECCOMP_FILTER_FLAG_WAIT_KILLABLE_RECV", 32 | 8),
+];
+
+const LANDLOCK_CREATE_RULESET_VERSION: c_uint = 1;
+
+/// Detect the features of the running kernel.
+///
+/// See the [module documentation](self) for how.
+pub fn probe() -> Features {
+    Features {
+        namespaces: NAMESPACES
+            .iter()
+            .filter(|(_, file)| Path::new("/proc/self/ns").join(file).exists())
+            .map(|(name, _)| *name)
+            .collect(),
+        user_namespaces: probe_user_namespaces(),
+        clone3: exists(unsafe { libc::syscall(libc::SYS_clone3, std::ptr::null::<u8>(), 0) }),
+        pidfd: probe_pidfd(),
+        mount_api: exists(unsafe { libc::syscall(libc::SYS_fsopen, std::ptr::null::<u8>(), 0) }),
+        idmapped_mounts: exists(unsafe {
+            libc::syscall(
+                libc::SYS_mount_setattr,
+                -1,
+                std::ptr::null::<u8>(),
+                0,
+                std::ptr::null::<u8>(),
+                0,
+            )
+        }),
+        landlock_abi: probe_landlock(),
+        seccomp: probe_seccomp(),
+        cgroup: probe_cgroup(),
+    }
+}
+
+/// Whether a syscall called with invalid arguments exists.
+fn exists(ret: c_long) -> bool {
+    ret >= 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::ENOSYS)
+}
+
+fn sysctl(path: &str) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn probe_user_namespaces() -> UserNamespaces {
+    // Only async-signal-safe calls in the child, the caller may have
+    // threads.
+    let allowed = match unsafe { libc::fork() } {
+        0 => unsafe {
+            libc::_exit(match libc::unshare(libc::CLONE_NEWUSER) {
+                0 => 0,
+                _ => 1,
+            })
+        },
+        -1 => false,
+        pid => {
+            let mut status = 0;
+            let ret = loop {
+                let ret = unsafe { libc::waitpid(pid, &mut status, 0) };
+                if ret != -1
+                    || std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted
+                {
+                    break ret;
+                }
+            };
+            ret == pid && libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+        }
+    };
+    UserNamespaces {
+        allowed,
+        unprivileged_userns_clone: sysctl("/proc/sys/kernel/unprivileged_userns_clone")
+            .map(|v| v != 0),
+        max_user_namespaces: sysctl("/proc/sys/user/max_user_namespaces"),
+        apparmor_restricted: sysctl("/proc/sys/kernel/apparmor_restrict_unprivileged_userns")
+            .map(|v| v != 0),
+    }
+}
+
+fn probe_pidfd() -> bool {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, libc::getpid(), 0) };
+    if fd >= 0 {
+        unsafe { libc::close(fd as i32) };
+    }
+    exists(fd)
+}
+
+fn probe_landlock() -> Option<u32> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<u8>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    u32::try_from(abi).ok().filter(|abi| *abi > 0)
+}
+
+fn probe_seccomp() -> Seccomp {
+    let action_avail = |action: u32| unsafe {
+        libc::syscall(libc::SYS_seccomp, SECCOMP_GET_ACTION_AVAIL, 0, &action) == 0
+    };
+    // Flags are checked before the filter is read, a NULL filter gives
+    // EFAULT with supported flags, and EINVAL otherwise.
+    let flag_supported = |flags: c_uint| {
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                SECCOMP_SET_MODE_FILTER,
+                flags,
+                std::ptr::null::<u8>(),
+            )
+        };
+        ret == -1 && std::io::Error::last_os_error().raw_os_error() == Some(libc::EFAULT)
+    };
+    Seccomp {
+        user_notif: action_avail(SECCOMP_RET_USER_NOTIF),
+        actions: SECCOMP_ACTIONS
+            .iter()
+            .filter(|(_, action)| action_avail(*action))
+            .map(|(name, _)| *name)
+            .collect(),
+        flags: SECCOMP_FLAGS
+            .iter()
+            .filter(|(_, flags)| flag_supported(*flags))
+            .map(|(name, _)| *name)
+            .collect(),
+    }
+}
+
+fn probe_cgroup() -> Cgroup {
+    use rustix::fs::{access, Access};
+
+    let root = Path::new("/sys/fs/cgroup");
+    if !root.join("cgroup.controllers").exists() {
+        return Cgroup::default();
+    }
+    let path = std::fs::read_to_string("/proc/self/cgroup")
+        .ok()
+        .and_then(|s| {
+            s.lines()
+                .find_map(|l| l.strip_prefix("0::").map(PathBuf::from))
+        });
+    let dir = path
+        .as_ref()
+        .map(|p| root.join(p.strip_prefix("/").unwrap_or(p)));
+    let writable = |p: &Path| access(p, Access::WRITE_OK).is_ok();
+    Cgroup {
+        v2: true,
+        delegated: dir.as_ref().is_some_and(|dir| {
+            writable(dir)
+                && writable(&dir.join("cgroup.procs"))
+                && writable(&dir.join("cgroup.subtree_control"))
+        }),
+        controllers: dir
+            .and_then(|dir| std::fs::read_to_string(dir.join("cgroup.controllers")).ok())
+            .map(|s| s.split_whitespace().map(String::from).collect())
+            .unwrap_or_default(),
+        path,
+    }
+}
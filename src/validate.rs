@@ -0,0 +1,254 @@
+//! Checks of a [`Wrap`] configuration, done before the child is cloned.
+use crate::{config, Wrap};
+use std::fmt;
+use std::path::Path;
+
+/// How bad a [`Diagnostic`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The setting is likely unintended, but the child can run.
+    Warning,
+    /// The child would fail to set up, [`Wrap::spawn()`] refuses it.
+    Error,
+}
+
+/// A problem found by [`Wrap::validate()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+/// Reads one namespace of a [`config::NamespaceSet`].
+type NsField = fn(&config::NamespaceSet) -> config::NamespaceItem;
+
+#[derive(Default)]
+struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    fn error(&mut self, message: String) {
+        self.0.push(Diagnostic {
+            severity: Severity::Error,
+            message,
+        });
+    }
+
+    fn warning(&mut self, message: String) {
+        self.0.push(Diagnostic {
+            severity: Severity::Warning,
+            message,
+        });
+    }
+
+    /// Paths inside the sandbox are resolved from its root.
+    fn absolute(&mut self, what: &str, path: &Path) {
+        if path.is_relative() {
+            self.error(format!(
+                "{} {} is not an absolute path",
+                what,
+                path.display()
+            ));
+        }
+    }
+
+    fn id_maps(&mut self, name: &str, maps: &[config::IdMap]) {
+        for (i, a) in maps.iter().enumerate() {
+            if a.size == 0 {
+                self.error(format!("{} map of {} has a size of 0", name, a.host_id));
+            }
+            for b in &maps[i + 1..] {
+                let overlap = |x: u32, y: u32| {
+                    (x as u64) < y as u64 + b.size as u64 && (y as u64) < x as u64 + a.size as u64
+                };
+                if overlap(a.host_id, b.host_id) || overlap(a.container_id, b.container_id) {
+                    self.error(format!(
+                        "{} maps {}->{} ({}) and {}->{} ({}) overlap",
+                        name, a.host_id, a.container_id, a.size, b.host_id, b.container_id, b.size
+                    ));
+                }
+            }
+        }
+    }
+}
+
+impl Wrap<'_> {
+    /// Check the configuration for settings the child cannot set up,
+    /// and for settings that are likely unintended.
+    ///
+    /// [`Self::spawn()`] calls this, and fails with
+    /// [`crate::error::Error::InvalidConfig`] if there is any
+    /// [`Severity::Error`].
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diag = Diagnostics::default();
+        let none = |ns: config::NamespaceItem| matches!(ns, config::NamespaceItem::None);
+        let has = |get: NsField| {
+            !none(get(&self.namespace_unshare)) || !none(get(&self.namespace_nsenter))
+        };
+
+        let types: [(&str, NsField); 7] = [
+            ("user", |s| s.user),
+            ("mount", |s| s.mount),
+            ("cgroup", |s| s.cgroup),
+            ("uts", |s| s.uts),
+            ("ipc", |s| s.ipc),
+            ("pid", |s| s.pid),
+            ("network", |s| s.network),
+        ];
+        for (name, get) in types {
+            if !none(get(&self.namespace_unshare)) && !none(get(&self.namespace_nsenter)) {
+                diag.error(format!("{} namespace is both entered and unshared", name));
+            }
+        }
+
+        if has(|s| s.time) {
+            diag.error("time namespaces are not supported".into());
+        }
+
+        let new_user = !none(self.namespace_unshare.user);
+        let id_maps = !self.uid_maps.is_empty() || !self.gid_maps.is_empty();
+        if id_maps && !new_user {
+            diag.error("id maps need an unshared user namespace".into());
+        }
+        if new_user && self.uid_maps.is_empty() {
+            diag.warning("no uid map, the child runs as the overflow user".into());
+        }
+        diag.id_maps("uid", &self.uid_maps);
+        diag.id_maps("gid", &self.gid_maps);
+        if let (Some(user), false) = (&self.process.user, self.uid_maps.is_empty()) {
+            let mapped = |maps: &[config::IdMap], id: u32| {
+                maps.iter()
+                    .any(|m| id >= m.container_id && (id - m.container_id) < m.size)
+            };
+            if !mapped(&self.uid_maps, user.uid) || !mapped(&self.gid_maps, user.gid) {
+                diag.warning(format!(
+                    "user {}:{} is not mapped in the user namespace",
+                    user.uid, user.gid
+                ));
+            }
+        }
+
+        let mounts = [
+            (self.sandbox_mnt, "sandbox_mnt"),
+            (self.root.is_some(), "root_dir"),
+            (self.overlay.is_some(), "overlay_root"),
+            (!self.fs_ops.is_empty(), "mounts, files, dirs and symlinks"),
+            (!self.masked_paths.is_empty(), "masked paths"),
+            (!self.readonly_paths.is_empty(), "readonly paths"),
+            (self.proc.is_some(), "proc_mount"),
+            (self.dev.is_some(), "dev"),
+            (self.etc_files.is_some(), "etc_files"),
+        ];
+        if !has(|s| s.mount) {
+            for (_, what) in mounts.iter().filter(|(set, _)| *set) {
+                diag.error(format!("a mount namespace is needed for {}", what));
+            }
+        }
+        if self.overlay.is_some() && self.root.is_some() {
+            diag.warning("root_dir is ignored, overlay_root is used as root".into());
+        }
+        if self.etc_files.is_some() {
+            if self.root.is_some() && self.overlay.is_none() {
+                diag.warning("etc_files create mount points in the root dir".into());
+            } else if !self.sandbox_mnt && self.overlay.is_none() {
+                diag.error("etc_files would be bound over the host /etc".into());
+            }
+        }
+
+        let creates = self
+            .fs_ops
+            .iter()
+            .any(|op| !matches!(op, config::FsOp::Mount(_) | config::FsOp::RemountRo { .. }));
+        if creates && self.overlay.is_none() {
+            if self.root.is_some() {
+                diag.warning("files, dirs and symlinks are created in the root dir".into());
+            } else if !self.sandbox_mnt {
+                diag.error("files, dirs and symlinks would be created on the host".into());
+            }
+        }
+
+        for op in &self.fs_ops {
+            match op {
+                config::FsOp::Mount(i) => {
+                    let mnt = &self.mounts[*i];
+                    diag.absolute("mount destination", &mnt.destination);
+                    if mnt.is_bind() && mnt.source.is_none() {
+                        diag.error(format!(
+                            "bind mount on {} has no source",
+                            mnt.destination.display()
+                        ));
+                    }
+                    let maps = [
+                        ("uid", &mnt.uid_mappings, &self.uid_maps),
+                        ("gid", &mnt.gid_mappings, &self.gid_maps),
+                    ];
+                    for (name, mnt_maps, maps) in maps {
+                        for m in mnt_maps {
+                            if crate::mount::to_host(m.container_id, m.size, maps).is_err() {
+                                diag.error(format!(
+                                    "{} mapping of the mount on {} uses unmapped ids {}..{}",
+                                    name,
+                                    mnt.destination.display(),
+                                    m.container_id,
+                                    m.container_id as u64 + m.size as u64
+                                ));
+                            }
+                        }
+                    }
+                }
+                config::FsOp::File { dest, .. } => diag.absolute("file", dest),
+                config::FsOp::Symlink { link, .. } => diag.absolute("symlink", link),
+                config::FsOp::Dir { path, .. } => diag.absolute("dir", path),
+                config::FsOp::RemountRo { path } => diag.absolute("remount", path),
+            }
+        }
+        for path in &self.masked_paths {
+            diag.absolute("masked path", path);
+        }
+        for path in &self.readonly_paths {
+            diag.absolute("readonly path", path);
+        }
+        if let Some(proc) = &self.proc {
+            diag.absolute("proc destination", &proc.destination);
+            if !has(|s| s.pid) {
+                diag.warning("proc is mounted without a pid namespace, it shows the host".into());
+            }
+        }
+        if let Some(cwd) = &self.process.cwd {
+            if cwd.is_relative() {
+                diag.warning(format!("working dir {} is relative", cwd.display()));
+            }
+        }
+
+        if self.hostname.is_some() && !has(|s| s.uts) {
+            diag.error("hostname needs a uts namespace".into());
+        }
+        let new_network = !none(self.namespace_unshare.network);
+        if !matches!(self.network, config::NetworkConfig::None) && !new_network {
+            diag.error("network needs an unshared network namespace".into());
+        }
+        if !self.port_forwards.is_empty() {
+            if !new_network {
+                diag.error("port forwards need an unshared network namespace".into());
+            } else if matches!(self.network, config::NetworkConfig::None) {
+                diag.warning("port forwards go to a loopback that is down".into());
+            }
+        }
+
+        if self.process.bin.is_empty()
+            && self.callbacks.is_empty()
+            && self.result_callback.is_none()
+        {
+            diag.warning("no program and no callback, the child does nothing".into());
+        }
+        diag.0
+    }
+}
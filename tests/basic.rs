@@ -1,36 +1,31 @@
-use nswrap;
 use nswrap::*;
 
 use rustix::fd::{FromRawFd, OwnedFd};
 
-
 const _TMP_DIR: &str = "/tmp/nswrap.test/";
 const _TMP_DIR1: &str = "/tmp/nswrap.test/test-1";
 const _TMP_DIR2: &str = "/tmp/nswrap.test/test-2";
 
-
 #[test]
 fn command_return_code() {
     let mut wrap = nswrap::Wrap::new_program("/bin/sh");
     wrap.arg("-c");
     wrap.arg("exit 25");
     let status = wrap.status().unwrap();
-    assert_eq!(status.code().unwrap(),25);
+    assert_eq!(status.code().unwrap(), 25);
 }
 
 #[test]
 fn command_return_code_2() {
     let mut wrap = nswrap::Wrap::new_program("/bin/sh");
-    wrap.args(vec!["-c","exit 25"]);
+    wrap.args(vec!["-c", "exit 25"]);
     let status = wrap.status().unwrap();
-    assert_eq!(status.code().unwrap(),25);
+    assert_eq!(status.code().unwrap(), 25);
 }
 
-
-
 fn make_test_dir() {
     use std::fs;
-    fs::remove_dir_all(_TMP_DIR);
+    let _ = fs::remove_dir_all(_TMP_DIR);
     fs::create_dir_all(_TMP_DIR1).unwrap();
     fs::create_dir_all(_TMP_DIR2).unwrap();
 }
@@ -48,7 +43,7 @@ fn bind_mount() {
         rustix::fs::mount(_TMP_DIR1, _TMP_DIR2, "", rustix::fs::MountFlags::BIND, "").unwrap();
         let mut file = File::create(_TMP_DIR2.to_owned() + "/foo.txt").unwrap();
         std::io::Write::write_all(&mut file, b"Hello, world!").unwrap();
-        return 0;
+        0
     };
     let mut wrap = Wrap::new();
     wrap.callback(cb).unshare(config::NamespaceType::User);
@@ -63,9 +58,7 @@ fn bind_mount() {
 
 #[test]
 fn callback_return_value() {
-    let cb = || {
-        return 16;
-    };
+    let cb = || 16;
     let mut wrap = Wrap::new();
     wrap.callback(cb).unshare(config::NamespaceType::User);
     let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
@@ -77,13 +70,10 @@ fn callback_return_value_in_thread() {
     use std::thread;
 
     let thread_join_handle = thread::spawn(move || {
-        let cb = || {
-            return 16;
-        };
+        let cb = || 16;
         let mut wrap = Wrap::new();
         wrap.callback(cb).unshare(config::NamespaceType::User);
-        let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
-        ret
+        wrap.spawn().unwrap().wait().unwrap().code().unwrap()
     });
 
     assert_eq!(thread_join_handle.join().unwrap(), 16);
@@ -99,11 +89,10 @@ fn panic_in_thread() {
         let cb = || panic!();
         let mut wrap = Wrap::new();
         wrap.callback(cb).unshare(config::NamespaceType::User);
-        let ret = wrap.status().unwrap();
-        ret
+        wrap.status().unwrap()
         // println!("{:?}", ret.wait_status)
     });
-    assert_eq!(thread_join_handle.join().unwrap().success(), true)
+    assert!(thread_join_handle.join().unwrap().success())
 }
 
 #[test]
@@ -112,9 +101,9 @@ fn tmpfs_root_sandbox_mnt() {
         use std::path::Path;
         let p = Path::new("/bin/sh");
         match p.exists() {
-            true => return 16,
-            false => return 32,
-        };
+            true => 16,
+            false => 32,
+        }
     };
     let mut binding = Wrap::new();
     let wrap = binding
@@ -134,7 +123,7 @@ fn raw_child_pipe() {
         let mut newfd = unsafe { OwnedFd::from_raw_fd(16) };
         rustix::io::dup2(write_end, &mut newfd).unwrap(); // Old fd will be dropped!
         rustix::io::write(newfd, b"16").unwrap();
-        return 42;
+        42
     };
     let mut binding = Wrap::new();
     let wrap = binding
@@ -149,4 +138,1549 @@ fn raw_child_pipe() {
     let mut buf: [u8; 2] = *b"00";
     rustix::io::read(read_end, &mut buf).unwrap();
     assert_eq!(buf, *b"16");
-}
\ No newline at end of file
+}
+#[test]
+fn network_loopback_only() {
+    let cb = || {
+        use std::net::{TcpListener, TcpStream};
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        match TcpStream::connect(listener.local_addr().unwrap()) {
+            Ok(_) => 16,
+            Err(_) => 32,
+        }
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Network)
+        .id_map_preset(config::IdMapPreset::Current)
+        .network(config::NetworkConfig::LoopbackOnly);
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+}
+
+#[test]
+// Creating a veth pair requires CAP_NET_ADMIN on the host.
+fn network_veth() {
+    use std::net::{IpAddr, Ipv4Addr, TcpListener};
+    use std::time::{Duration, Instant};
+    if util::get_uid() != 0 {
+        eprintln!("skipping network_veth: creating a veth pair needs root");
+        return;
+    }
+    let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let cb = move || {
+        use std::net::TcpStream;
+        match TcpStream::connect(("10.231.0.1", port)) {
+            Ok(_) => 16,
+            Err(_) => 32,
+        }
+    };
+    // Unique names, so that runs of the tests do not collide.
+    let pid = std::process::id();
+    let veth = config::VethConfigBuilder::default()
+        .host_name(format!("nsw{}h", pid))
+        .container_name(format!("nsw{}c", pid))
+        .host_address(Some((IpAddr::V4(Ipv4Addr::new(10, 231, 0, 1)), 24)))
+        .container_address(Some((IpAddr::V4(Ipv4Addr::new(10, 231, 0, 2)), 24)))
+        .mtu(Some(1400))
+        .build()
+        .unwrap();
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::Network)
+        .network(config::NetworkConfig::Veth(veth));
+    let mut child = wrap.spawn().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while let Err(e) = listener.accept() {
+        assert_eq!(std::io::ErrorKind::WouldBlock, e.kind());
+        assert!(Instant::now() < deadline, "no connection from the child");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(16, child.wait().unwrap().code().unwrap());
+}
+
+#[test]
+fn forward_port_loopback() {
+    use std::io::Read;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    let host_addr: SocketAddr = {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        l.local_addr().unwrap()
+    };
+    let cb = || {
+        use std::io::Write;
+        let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+        let (mut conn, _) = listener.accept().unwrap();
+        conn.write_all(b"16").unwrap();
+        16
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Network)
+        .id_map_preset(config::IdMapPreset::Current)
+        .network(config::NetworkConfig::LoopbackOnly)
+        .forward_port(host_addr, 8080);
+    let mut child = wrap.spawn().unwrap();
+    // The child may not be listening yet, retry until it answers.
+    let mut buf = Vec::new();
+    for _ in 0..100 {
+        let mut stream = TcpStream::connect(host_addr).unwrap();
+        buf.clear();
+        stream.read_to_end(&mut buf).unwrap();
+        if !buf.is_empty() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert_eq!(buf, b"16");
+    assert_eq!(16, child.wait().unwrap().code().unwrap());
+}
+
+#[test]
+fn forward_port_closed_on_wait() {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    let host_addr: SocketAddr = {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        l.local_addr().unwrap()
+    };
+    let cb = || {
+        let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+        let (mut conn, _) = listener.accept().unwrap();
+        conn.write_all(b"16").unwrap();
+        16
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Network)
+        .id_map_preset(config::IdMapPreset::Current)
+        .network(config::NetworkConfig::LoopbackOnly)
+        .forward_port(host_addr, 8080);
+    let mut child = wrap.spawn().unwrap();
+    let mut stream = loop {
+        let mut stream = TcpStream::connect(host_addr).unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        if !buf.is_empty() {
+            break stream;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    };
+    assert_eq!(16, child.wait().unwrap().code().unwrap());
+    // The forwarded connection is closed, writing to it is refused.
+    stream.write_all(b"x").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    assert!(stream.write_all(b"x").is_err());
+}
+
+#[test]
+fn forward_port_failed_parent_callback() {
+    use std::net::{SocketAddr, TcpListener};
+    let host_addr: SocketAddr = {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        l.local_addr().unwrap()
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(|| 0)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Network)
+        .id_map_preset(config::IdMapPreset::Current)
+        .network(config::NetworkConfig::LoopbackOnly)
+        .forward_port(host_addr, 8080)
+        .parent_callback(|_| Err(error::Error::OsErrno(libc::EPERM)));
+    assert!(wrap.spawn().is_err());
+}
+
+#[test]
+fn generated_etc_files() {
+    let cb = || {
+        let passwd = std::fs::read_to_string("/etc/passwd").unwrap();
+        let hosts = std::fs::read_to_string("/etc/hosts").unwrap();
+        let resolv = std::fs::read_to_string("/etc/resolv.conf").unwrap();
+        let readonly = std::fs::write("/etc/passwd", "").is_err();
+        match passwd.starts_with("root:x:0:0:")
+            && hosts.contains("nswrap-test")
+            && resolv == "nameserver 10.0.0.53\n"
+            && readonly
+        {
+            true => 16,
+            false => 32,
+        }
+    };
+    let etc = config::EtcFilesBuilder::default()
+        .nameservers(vec!["10.0.0.53".parse().unwrap()])
+        .build()
+        .unwrap();
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .unshare(config::NamespaceType::Uts)
+        .sandbox_mnt(true)
+        .id_map_preset(config::IdMapPreset::Root)
+        .hostname("nswrap-test")
+        .etc_files(etc);
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+}
+
+#[test]
+fn generated_etc_files_root_dir() {
+    use std::fs;
+    let root = "/tmp/nswrap.test-etc";
+    let _ = fs::remove_dir_all(root);
+    fs::create_dir_all(format!("{}/etc", root)).unwrap();
+    fs::write(format!("{}/etc/passwd", root), "host\n").unwrap();
+    let cb = || {
+        let passwd = std::fs::read_to_string("/etc/passwd").unwrap();
+        match passwd.starts_with("root:x:0:0:") {
+            true => 16,
+            false => 32,
+        }
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .id_map_preset(config::IdMapPreset::Root)
+        .root_dir(root, false)
+        .etc_files(config::EtcFilesBuilder::default().build().unwrap());
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+    assert_eq!(
+        "host\n",
+        fs::read_to_string(format!("{}/etc/passwd", root)).unwrap()
+    );
+    // Only an empty mount point is created.
+    assert_eq!(
+        "",
+        fs::read_to_string(format!("{}/etc/hosts", root)).unwrap()
+    );
+}
+
+#[test]
+fn overlay_root_tmpfs_upper() {
+    use std::fs;
+    let lower = "/tmp/nswrap.test-overlay/lower";
+    let _ = fs::remove_dir_all("/tmp/nswrap.test-overlay");
+    fs::create_dir_all(lower).unwrap();
+    fs::write(format!("{}/hello", lower), "hello").unwrap();
+    let cb = || {
+        let hello = std::fs::read_to_string("/hello").unwrap_or_default();
+        let written = std::fs::write("/new", "new").is_ok();
+        match hello == "hello" && written {
+            true => 16,
+            false => 32,
+        }
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .id_map_preset(config::IdMapPreset::Current)
+        .overlay_root([lower], None);
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+    assert!(!std::path::Path::new(&format!("{}/new", lower)).exists());
+}
+
+#[test]
+fn setup_error_is_reported() {
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(|| 0)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .id_map_preset(config::IdMapPreset::Current)
+        .overlay_root(["/nonexistent/nswrap"], None);
+    match wrap.spawn() {
+        Err(error::Error::SetupFailed(step, errno)) => {
+            assert_eq!(step, "overlay lower dir");
+            assert_eq!(errno, libc::ENOENT);
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn overlay_changeset() {
+    use nswrap::changeset::ChangeKind;
+    use std::fs;
+    use std::path::Path;
+    let base = "/tmp/nswrap.test-changeset";
+    let lower = format!("{}/lower", base);
+    let state = format!("{}/state", base);
+    let _ = fs::remove_dir_all(base);
+    fs::create_dir_all(format!("{}/dir", lower)).unwrap();
+    fs::write(format!("{}/modified", lower), "old").unwrap();
+    fs::write(format!("{}/deleted", lower), "old").unwrap();
+    let cb = || {
+        fs::write("/modified", "new").unwrap();
+        fs::remove_file("/deleted").unwrap();
+        fs::write("/dir/added", "new").unwrap();
+        0
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .id_map_preset(config::IdMapPreset::Current)
+        .overlay_root([&lower], Some(state.into()));
+    let mut child = wrap.spawn().unwrap();
+    assert!(child.wait().unwrap().success());
+
+    let changes = child.changeset().unwrap();
+    let find = |p: &str| {
+        changes
+            .changes()
+            .iter()
+            .find(|c| c.path == Path::new(p))
+            .map(|c| c.kind)
+    };
+    assert_eq!(find("modified"), Some(ChangeKind::Modified));
+    assert_eq!(find("deleted"), Some(ChangeKind::Deleted));
+    assert_eq!(find("dir/added"), Some(ChangeKind::Added));
+    assert_eq!(find("dir"), None);
+
+    let mut archive = Vec::new();
+    changes.write_tar(&mut archive).unwrap();
+    let mut names = tar::Archive::new(archive.as_slice())
+        .entries()
+        .unwrap()
+        .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec![".wh.deleted", "dir/added", "modified"]);
+}
+
+#[test]
+fn ro_bind_into_sandbox() {
+    use std::fs;
+    let src = "/tmp/nswrap.test-ro-bind";
+    let _ = fs::remove_dir_all(src);
+    fs::create_dir_all(src).unwrap();
+    fs::write(format!("{}/file", src), "data").unwrap();
+    let cb = || {
+        let data = fs::read_to_string("/data/file").unwrap_or_default();
+        let readonly = fs::write("/data/new", "").is_err();
+        match data == "data" && readonly {
+            true => 16,
+            false => 32,
+        }
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .sandbox_mnt(true)
+        .id_map_preset(config::IdMapPreset::Current)
+        .ro_bind(src, "/data");
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+}
+
+#[test]
+// Setting an id mapping on a host filesystem requires root.
+fn idmapped_bind_mount() {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    if util::get_uid() != 0 {
+        return;
+    }
+    let src = "/tmp/nswrap.test-idmap";
+    let _ = fs::remove_dir_all(src);
+    fs::create_dir_all(src).unwrap();
+    fs::write(format!("{}/file", src), "data").unwrap();
+    std::os::unix::fs::chown(format!("{}/file", src), Some(1234), Some(1234)).unwrap();
+    let cb = || {
+        let meta = fs::metadata("/data/file").unwrap();
+        match (meta.uid(), meta.gid()) {
+            (0, 0) => 16,
+            _ => 32,
+        }
+    };
+    let map = config::IdMapBuilder::default()
+        .host_id(1234)
+        .container_id(0)
+        .size(1)
+        .build()
+        .unwrap();
+    let mnt = config::MountBuilder::default()
+        .destination("/data".into())
+        .typ(None)
+        .source(Some(src.into()))
+        .options(Some(vec!["rbind".into()]))
+        .uid_mappings(vec![map.clone()])
+        .gid_mappings(vec![map])
+        .build()
+        .unwrap();
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .sandbox_mnt(true)
+        .id_map_preset(config::IdMapPreset::Root)
+        .mount(mnt);
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+}
+
+#[test]
+fn separate_uid_and_gid_maps() {
+    let cb = || {
+        let uid = rustix::process::getuid().as_raw();
+        let gid = rustix::process::getgid().as_raw();
+        match (uid, gid) {
+            (0, 5) => 16,
+            _ => 32,
+        }
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .uid_map(util::get_uid(), 0, 1)
+        .gid_map(util::get_gid(), 5, 1);
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+}
+
+#[test]
+fn fsconfig_error_is_reported() {
+    let mnt = config::MountBuilder::default()
+        .destination("/scratch".into())
+        .typ(Some("tmpfs".into()))
+        .source(None)
+        .options(Some(vec!["size=lots".into()]))
+        .build()
+        .unwrap();
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(|| 0)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .sandbox_mnt(true)
+        .id_map_preset(config::IdMapPreset::Current)
+        .mount(mnt);
+    match wrap.spawn() {
+        Err(error::Error::SetupFailed(step, errno)) => {
+            assert!(step.starts_with("mount /scratch: fsconfig size=lots"));
+            assert_eq!(errno, libc::EINVAL);
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn tmpfs_mount_in_sandbox() {
+    let mnt = config::MountBuilder::default()
+        .destination("/scratch".into())
+        .typ(Some("tmpfs".into()))
+        .source(None)
+        .options(Some(vec!["ro".into(), "mode=700".into()]))
+        .build()
+        .unwrap();
+    let cb = || {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata("/scratch").unwrap().permissions().mode();
+        let readonly = std::fs::write("/scratch/file", "").is_err();
+        match mode & 0o777 == 0o700 && readonly {
+            true => 16,
+            false => 32,
+        }
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .sandbox_mnt(true)
+        .id_map_preset(config::IdMapPreset::Current)
+        .mount(mnt);
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+}
+
+#[test]
+fn bind_source_is_checked() {
+    use std::os::unix::fs::FileTypeExt;
+    let dir = "/tmp/nswrap.test-sources";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let fifo = format!("{}/fifo", dir);
+    let socket = format!("{}/socket", dir);
+    let link = format!("{}/link", dir);
+    nix::unistd::mkfifo(fifo.as_str(), nix::sys::stat::Mode::S_IRWXU).unwrap();
+    let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+    std::os::unix::fs::symlink(&fifo, &link).unwrap();
+
+    // FIFOs and sockets can be bound like other files.
+    let cb = || {
+        let fifo = std::fs::metadata("/fifo").unwrap().file_type();
+        let socket = std::fs::metadata("/socket").unwrap().file_type();
+        match fifo.is_fifo() && socket.is_socket() {
+            true => 16,
+            false => 32,
+        }
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .sandbox_mnt(true)
+        .id_map_preset(config::IdMapPreset::Current)
+        .bind(&fifo, "/fifo")
+        .bind(&socket, "/socket");
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+
+    // A symlink is not followed.
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(|| 0)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .sandbox_mnt(true)
+        .id_map_preset(config::IdMapPreset::Current)
+        .bind(&link, "/link");
+    match wrap.spawn() {
+        Err(error::Error::SetupFailed(step, errno)) => {
+            assert_eq!(step, format!("bind mount source: {}", link));
+            assert_eq!(errno, libc::ELOOP);
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn bind_on_sandbox_root() {
+    let mut wrap = Wrap::new_program("/bin/sh");
+    wrap.args([
+        "-c",
+        "test -f /etc/passwd && touch /tmp/new && ! test -e /hidden \
+         && ! test -e /oldroot && ! touch /usr/new 2>/dev/null && exit 3",
+    ])
+    .unshare(config::NamespaceType::User)
+    .unshare(config::NamespaceType::Mount)
+    .sandbox_mnt(true)
+    .id_map_preset(config::IdMapPreset::Current)
+    .dir("/hidden", 0o755)
+    .ro_bind("/", "/")
+    .mount(
+        config::MountBuilder::default()
+            .destination("/tmp".into())
+            .typ(Some("tmpfs".into()))
+            .source(Some("tmpfs".into()))
+            .options(None)
+            .build()
+            .unwrap(),
+    );
+    assert_eq!(Some(3), wrap.status().unwrap().code());
+}
+
+#[test]
+fn sandbox_mnt_drops_old_root() {
+    let cb = || {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata("/").unwrap().permissions().mode();
+        let old_root = std::path::Path::new("/oldroot").exists();
+        match mode & 0o7777 == 0o700 && !old_root {
+            true => 16,
+            false => 32,
+        }
+    };
+    let cfg = config::SandboxMntBuilder::default()
+        .size(Some(1 << 20))
+        .mode(Some(0o700))
+        .nr_inodes(Some(64))
+        .staging("/tmp/nswrap.test-staging".into())
+        .propagation(config::Propagation::Private)
+        .build()
+        .unwrap();
+    std::fs::create_dir_all(cfg.staging()).unwrap();
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .id_map_preset(config::IdMapPreset::Current)
+        .sandbox_mnt_config(cfg);
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+}
+
+#[test]
+fn masked_and_readonly_paths() {
+    use std::fs;
+    let src = "/tmp/nswrap.test-mask";
+    let _ = fs::remove_dir_all(src);
+    fs::create_dir_all(format!("{}/hidden", src)).unwrap();
+    fs::create_dir_all(format!("{}/ro", src)).unwrap();
+    fs::write(format!("{}/hidden/file", src), "data").unwrap();
+    fs::write(format!("{}/secret", src), "data").unwrap();
+    let cb = || {
+        let secret = fs::read_to_string("/data/secret").unwrap_or_default();
+        let hidden = fs::read_dir("/data/hidden").map(|d| d.count()).unwrap_or(1);
+        let readonly = fs::write("/data/ro/new", "").is_err();
+        let writable = fs::write("/data/new", "").is_ok();
+        match secret.is_empty() && hidden == 0 && readonly && writable {
+            true => 16,
+            false => 32,
+        }
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .sandbox_mnt(true)
+        .id_map_preset(config::IdMapPreset::Current)
+        .bind(src, "/data")
+        .default_masks()
+        .mask_path("/data/secret")
+        .mask_path("/data/hidden")
+        .readonly_path("/data/ro");
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+}
+
+#[test]
+fn proc_mount_in_pid_namespace() {
+    let cb = || {
+        let pids = std::fs::read_dir("/proc")
+            .unwrap()
+            .filter_map(|e| e.unwrap().file_name().into_string().ok())
+            .filter(|name| name.parse::<u32>().is_ok())
+            .collect::<Vec<_>>();
+        let subset = !std::path::Path::new("/proc/sys").exists();
+        match pids == ["1"] && subset && std::process::id() == 1 {
+            true => 16,
+            false => 32,
+        }
+    };
+    let proc = config::ProcOptionsBuilder::default()
+        .hidepid(Some(config::HidePid::Invisible))
+        .subset_pid(true)
+        .build()
+        .unwrap();
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .unshare(config::NamespaceType::Pid)
+        .sandbox_mnt(true)
+        .id_map_preset(config::IdMapPreset::Current)
+        .proc_mount(proc);
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+}
+
+#[test]
+fn minimal_dev() {
+    use std::io::{Read, Write};
+    let cb = || {
+        let null = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .and_then(|mut f| f.write_all(b"data"))
+            .is_ok();
+        let mut buf = [1u8; 4];
+        let zero = std::fs::File::open("/dev/zero")
+            .and_then(|mut f| f.read_exact(&mut buf))
+            .is_ok()
+            && buf == [0; 4];
+        let ptmx = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/ptmx")
+            .is_ok();
+        let sda = std::path::Path::new("/dev/sda").exists();
+        match null && zero && ptmx && !sda {
+            true => 16,
+            false => 32,
+        }
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .sandbox_mnt(true)
+        .id_map_preset(config::IdMapPreset::Current)
+        .dev(config::DevConfig::default());
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+}
+
+#[test]
+fn files_from_memory_and_fd() {
+    use std::io::Write;
+    let (read_end, write_end) = rustix::pipe::pipe().unwrap();
+    std::fs::File::from(write_end)
+        .write_all(b"from fd")
+        .unwrap();
+    let cb = || {
+        use std::os::unix::fs::PermissionsExt;
+        let secret = std::fs::read_to_string("/run/secrets/token").unwrap_or_default();
+        let mode = std::fs::metadata("/run/secrets/token")
+            .map(|m| m.permissions().mode() & 0o777)
+            .unwrap_or(0);
+        let conf = std::fs::read_to_string("/etc/app.conf").unwrap_or_default();
+        match secret == "s3cret" && mode == 0o600 && conf == "from fd" {
+            true => 16,
+            false => 32,
+        }
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .sandbox_mnt(true)
+        .id_map_preset(config::IdMapPreset::Current)
+        .file("/run/secrets/token", "s3cret", 0o600)
+        .file_from_fd("/etc/app.conf", read_end);
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+}
+
+#[test]
+fn files_in_root_dir_and_reused_fd() {
+    use std::fs;
+    let root = "/tmp/nswrap.test-files";
+    let _ = fs::remove_dir_all(root);
+    fs::create_dir_all(root).unwrap();
+    fs::write(format!("{}/motd", root), "host").unwrap();
+    fs::write(format!("{}/src", root), "from fd").unwrap();
+    let src = fs::File::open(format!("{}/src", root)).unwrap();
+    let cb = || {
+        let motd = std::fs::read_to_string("/motd").unwrap_or_default();
+        let conf = std::fs::read_to_string("/conf").unwrap_or_default();
+        match motd == "hello" && conf == "from fd" {
+            true => 16,
+            false => 32,
+        }
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .root_dir(root, false)
+        .id_map_preset(config::IdMapPreset::Current)
+        .file("/motd", "hello", 0o644)
+        .file_from_fd("/conf", src.into());
+    for _ in 0..2 {
+        let ret = wrap.callback(cb).spawn().unwrap().wait().unwrap();
+        let ret = ret.code().unwrap();
+        assert_eq!(16, ret);
+    }
+    assert_eq!(
+        "host",
+        fs::read_to_string(format!("{}/motd", root)).unwrap()
+    );
+    assert_eq!("", fs::read_to_string(format!("{}/conf", root)).unwrap());
+}
+
+#[test]
+fn symlinks_and_dirs_in_order() {
+    let cb = || {
+        use std::os::unix::fs::PermissionsExt;
+        let sh = std::fs::read_to_string("/bin/sh").unwrap_or_default();
+        let mode = std::fs::metadata("/var/tmp")
+            .map(|m| m.permissions().mode() & 0o7777)
+            .unwrap_or(0);
+        match sh == "#!" && mode == 0o1777 {
+            true => 16,
+            false => 32,
+        }
+    };
+    let mut binding = Wrap::new();
+    let wrap = binding
+        .callback(cb)
+        .unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .sandbox_mnt(true)
+        .id_map_preset(config::IdMapPreset::Current)
+        .dir("/usr/bin", 0o755)
+        .symlink("usr/bin", "/bin")
+        .symlink("usr/bin", "/bin")
+        .file("/bin/sh", "#!", 0o755)
+        .dir("/var/tmp", 0o1777);
+    let ret = wrap.spawn().unwrap().wait().unwrap().code().unwrap();
+    assert_eq!(16, ret);
+}
+
+/// Create a bundle with an empty rootfs, returning the mounts that make
+/// the host's programs available in it.
+#[cfg(feature = "serde")]
+fn oci_test_bundle(bundle: &str) -> Vec<String> {
+    let _ = std::fs::remove_dir_all(bundle);
+    std::fs::create_dir_all(format!("{}/data", bundle)).unwrap();
+    std::fs::create_dir_all(format!("{}/rootfs/usr", bundle)).unwrap();
+    std::fs::create_dir_all(format!("{}/rootfs/mnt", bundle)).unwrap();
+    // Mirror the layout of the host, which might have a merged /usr.
+    let mut mounts = vec![
+        r#"{ "destination": "/usr", "source": "/usr", "options": ["rbind", "ro"] }"#.to_string(),
+    ];
+    for dir in ["bin", "lib", "lib64"] {
+        let host = format!("/{}", dir);
+        match std::fs::read_link(&host) {
+            Ok(target) => {
+                std::os::unix::fs::symlink(target, format!("{}/rootfs/{}", bundle, dir)).unwrap()
+            }
+            Err(_) if std::path::Path::new(&host).exists() => mounts.push(format!(
+                r#"{{ "destination": "{}", "source": "{}", "options": ["rbind", "ro"] }}"#,
+                host, host
+            )),
+            Err(_) => (),
+        }
+    }
+    mounts
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn oci_bundle() {
+    let bundle = "/tmp/nswrap.test-bundle";
+    let mut mounts = oci_test_bundle(bundle);
+    std::fs::write(format!("{}/data/file", bundle), "data").unwrap();
+    mounts.push(
+        r#"{ "destination": "/mnt", "type": "bind", "source": "data", "options": ["rbind", "ro"] }"#
+            .into(),
+    );
+    mounts.push(r#"{ "destination": "/opt", "type": "bind", "source": "data" }"#.into());
+    let uid = rustix::process::getuid().as_raw();
+    let gid = rustix::process::getgid().as_raw();
+    let config = format!(
+        r#"{{
+            "ociVersion": "1.0.2",
+            "process": {{
+                "user": {{ "uid": 0, "gid": 0 }},
+                "args": ["/bin/sh", "-c", "test \"$(uname -n)\" = oci && test \"$(cat /mnt/file)\" = data && test \"$(cat /opt/file)\" = data && test \"$FOO\" = bar && test -z \"$HOME\" && ! touch /new && exit 16"],
+                "env": ["FOO=bar", "PATH=/usr/bin:/bin"],
+                "cwd": "/"
+            }},
+            "root": {{ "path": "rootfs", "readonly": true }},
+            "hostname": "oci",
+            "mounts": [{}],
+            "linux": {{
+                "namespaces": [{{ "type": "user" }}, {{ "type": "mount" }}, {{ "type": "uts" }}],
+                "uidMappings": [{{ "containerID": 0, "hostID": {}, "size": 1 }}],
+                "gidMappings": [{{ "containerID": 0, "hostID": {}, "size": 1 }}]
+            }}
+        }}"#,
+        mounts.join(","),
+        uid,
+        gid
+    );
+    std::fs::write(format!("{}/config.json", bundle), config).unwrap();
+    let ret = Wrap::from_oci_bundle(bundle)
+        .unwrap()
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .code()
+        .unwrap();
+    assert_eq!(16, ret);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn oci_runtime_failed_create() {
+    use std::process::Command;
+
+    let bundle = "/tmp/nswrap.test-runtime-failed";
+    let root = "/tmp/nswrap.test-runtime-failed-state";
+    let _ = std::fs::remove_dir_all(root);
+    let _ = std::fs::remove_dir_all(bundle);
+    std::fs::create_dir_all(format!("{}/rootfs", bundle)).unwrap();
+    let config = |hook: &str| {
+        format!(
+            r#"{{
+                "ociVersion": "1.0.2",
+                "process": {{ "args": ["/bin/true"], "cwd": "/" }},
+                "root": {{ "path": "rootfs" }},
+                "hooks": {{ "createRuntime": [{{ "path": "{}" }}] }},
+                "linux": {{
+                    "namespaces": [{{ "type": "user" }}, {{ "type": "mount" }}],
+                    "uidMappings": [{{ "containerID": 0, "hostID": {}, "size": 1 }}],
+                    "gidMappings": [{{ "containerID": 0, "hostID": {}, "size": 1 }}]
+                }}
+            }}"#,
+            hook,
+            rustix::process::getuid().as_raw(),
+            rustix::process::getgid().as_raw()
+        )
+    };
+    let create = |pid_file: &str| {
+        Command::new(env!("CARGO_BIN_EXE_nswrap-runtime"))
+            .args(["--root", root, "create", "--bundle", bundle])
+            .args(["--pid-file", pid_file, "test"])
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .unwrap()
+    };
+
+    // A failed hook fails the spawn, and a missing pid file dir fails
+    // after it.
+    std::fs::write(format!("{}/config.json", bundle), config("/bin/false")).unwrap();
+    assert!(!create(&format!("{}/pid", bundle)).success());
+    assert!(!std::path::Path::new(&format!("{}/test", root)).exists());
+    std::fs::write(format!("{}/config.json", bundle), config("/bin/true")).unwrap();
+    assert!(!create(&format!("{}/missing/pid", bundle)).success());
+    assert!(!std::path::Path::new(&format!("{}/test", root)).exists());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn oci_runtime_lifecycle() {
+    use std::process::Command;
+
+    let bundle = "/tmp/nswrap.test-runtime-bundle";
+    let root = "/tmp/nswrap.test-runtime-state";
+    let _ = std::fs::remove_dir_all(root);
+    let mut mounts = oci_test_bundle(bundle);
+    mounts.push(r#"{ "destination": "/mnt", "source": "data", "options": ["rbind"] }"#.into());
+    let hook = |script: String| {
+        format!(
+            r#"{{ "path": "/bin/sh", "args": ["sh", "-c", "{}"], "env": ["PATH=/usr/bin:/bin"] }}"#,
+            script
+        )
+    };
+    let data = format!("{}/data", bundle);
+    let uid = rustix::process::getuid().as_raw();
+    let gid = rustix::process::getgid().as_raw();
+    let config = format!(
+        r#"{{
+            "ociVersion": "1.0.2",
+            "process": {{
+                "args": ["/bin/sh", "-c", "echo started > /mnt/out; exec sleep 100"],
+                "env": ["PATH=/usr/bin:/bin"],
+                "cwd": "/"
+            }},
+            "root": {{ "path": "rootfs" }},
+            "mounts": [{}],
+            "hooks": {{
+                "createRuntime": [{}],
+                "createContainer": [{}],
+                "startContainer": [{}],
+                "poststart": [{}],
+                "poststop": [{}]
+            }},
+            "linux": {{
+                "namespaces": [{{ "type": "user" }}, {{ "type": "mount" }}, {{ "type": "pid" }}],
+                "uidMappings": [{{ "containerID": 0, "hostID": {}, "size": 1 }}],
+                "gidMappings": [{{ "containerID": 0, "hostID": {}, "size": 1 }}]
+            }}
+        }}"#,
+        mounts.join(","),
+        hook(format!("cat > {}/create_runtime", data)),
+        hook(format!(
+            "readlink /proc/self/ns/mnt > {}/create_container",
+            data
+        )),
+        hook("cat > /mnt/start_container".into()),
+        hook(format!("cat > {}/poststart", data)),
+        hook(format!("cat > {}/poststop", data)),
+        uid,
+        gid
+    );
+    std::fs::write(format!("{}/config.json", bundle), config).unwrap();
+    let hook_state = |name: &str| -> serde_json::Value {
+        serde_json::from_slice(&std::fs::read(format!("{}/{}", data, name)).unwrap()).unwrap()
+    };
+
+    let runtime = |args: &[&str]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_nswrap-runtime"))
+            .arg("--root")
+            .arg(root)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "{:?}: {:?}", args, out);
+        String::from_utf8(out.stdout).unwrap()
+    };
+    let status = |state: String| {
+        let state: serde_json::Value = serde_json::from_str(&state).unwrap();
+        state["status"].as_str().unwrap().to_string()
+    };
+    let out = format!("{}/data/out", bundle);
+
+    // The container inherits stdio, which must not be the pipes that
+    // `output()` reads to the end.
+    let created = Command::new(env!("CARGO_BIN_EXE_nswrap-runtime"))
+        .args(["--root", root, "create", "--bundle", bundle, "test"])
+        .stdout(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(created.success());
+    let state: serde_json::Value = serde_json::from_str(&runtime(&["state", "test"])).unwrap();
+    assert_eq!("created", state["status"]);
+    assert_eq!("creating", hook_state("create_runtime")["status"]);
+    assert_eq!(state["pid"], hook_state("create_runtime")["pid"]);
+    assert_ne!(
+        std::fs::read_link("/proc/self/ns/mnt")
+            .unwrap()
+            .to_str()
+            .unwrap(),
+        std::fs::read_to_string(format!("{}/create_container", data))
+            .unwrap()
+            .trim_end()
+    );
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert!(!std::path::Path::new(&out).exists());
+    assert!(!std::path::Path::new(&format!("{}/start_container", data)).exists());
+
+    runtime(&["start", "test"]);
+    assert_eq!(state["pid"], hook_state("poststart")["pid"]);
+    assert_eq!("running", status(runtime(&["state", "test"])));
+    for _ in 0..50 {
+        if std::path::Path::new(&out).exists() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert_eq!("started\n", std::fs::read_to_string(&out).unwrap());
+    assert_eq!(state["pid"], hook_state("start_container")["pid"]);
+
+    runtime(&["kill", "test", "SIGKILL"]);
+    for _ in 0..50 {
+        if status(runtime(&["state", "test"])) == "stopped" {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert_eq!("stopped", status(runtime(&["state", "test"])));
+    runtime(&["delete", "test"]);
+    assert!(!std::path::Path::new(&format!("{}/test", root)).exists());
+    assert_eq!("stopped", hook_state("poststop")["status"]);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn oci_start_container_failed() {
+    let spec: oci::Spec = serde_json::from_str(
+        r#"{ "ociVersion": "1.0.2", "hooks": { "startContainer": [{ "path": "/bin/false" }] } }"#,
+    )
+    .unwrap();
+    let state = oci::State {
+        oci_version: "1.0.2".into(),
+        id: "test".into(),
+        status: oci::Status::Creating,
+        pid: None,
+        bundle: "/".into(),
+        annotations: Default::default(),
+    };
+    let mut wrap = Wrap::new_program("/bin/true");
+    wrap.unshare(config::NamespaceType::User)
+        .id_map_preset(config::IdMapPreset::Current);
+    spec.apply_hooks(&mut wrap, &state).unwrap();
+    let status = wrap.status().unwrap();
+    assert_eq!(Some(oci::START_CONTAINER_FAILED), status.code());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn oci_hook_timeout() {
+    let hook = oci::Hook {
+        path: "/bin/sh".into(),
+        args: vec!["sh".into(), "-c".into(), "exec sleep 10".into()],
+        timeout: Some(1),
+        ..Default::default()
+    };
+    let state = oci::State {
+        oci_version: "1.0.2".into(),
+        id: "test".into(),
+        status: oci::Status::Creating,
+        pid: None,
+        bundle: "/".into(),
+        annotations: Default::default(),
+    };
+    let start = std::time::Instant::now();
+    assert!(matches!(hook.run(&state), Err(error::Error::HookFailed(_))));
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn wrap_config_round_trip() {
+    let mut wrap = Wrap::new_program("/bin/sh");
+    wrap.args([
+        "-c",
+        "test \"$(uname -n)\" = snap && test \"$FOO\" = bar && exit 16",
+    ])
+    .env("FOO", "bar")
+    .env_remove("HOME")
+    .unshare(config::NamespaceType::User)
+    .unshare(config::NamespaceType::Uts)
+    .id_map_preset(config::IdMapPreset::Current)
+    .hostname("snap")
+    .bind("/usr", "/mnt")
+    .file("/etc/motd", "hello", 0o644)
+    .mask_path("/proc/kcore");
+    let cfg = wrap.config();
+
+    let json = cfg.to_json().unwrap();
+    let from_json = config::WrapConfig::from_json(&json).unwrap();
+    assert_eq!(json, from_json.to_json().unwrap());
+    let toml = cfg.to_toml().unwrap();
+    let from_toml = config::WrapConfig::from_toml(&toml).unwrap();
+    assert_eq!(toml, from_toml.to_toml().unwrap());
+    assert_eq!(json, from_toml.to_json().unwrap());
+
+    // Without the mount namespace, only the rest of the config applies.
+    let mut cfg = config::WrapConfig::from_json(
+        r#"{
+            "process": {
+                "bin": "/bin/sh",
+                "args": ["-c", "test \"$(uname -n)\" = snap && test \"$FOO\" = bar && exit 16"],
+                "env": { "FOO": { "set": "bar" } }
+            },
+            "namespace_unshare": { "user": "unshare", "uts": "unshare" },
+            "hostname": "snap"
+        }"#,
+    )
+    .unwrap();
+    cfg = config::WrapConfig::from_toml(&cfg.to_toml().unwrap()).unwrap();
+    let ret = Wrap::from_config(cfg).status().unwrap();
+    assert_eq!(Some(16), ret.code());
+
+    let (r, _w) = rustix::pipe::pipe().unwrap();
+    assert!(Wrap::new()
+        .file_from_fd("/f", r)
+        .config()
+        .to_json()
+        .is_err());
+    assert!(Wrap::new()
+        .nsenter(config::NamespaceType::Network, 0)
+        .config()
+        .to_json()
+        .is_err());
+    assert!(config::WrapConfig::from_json(
+        r#"{ "namespace_nsenter": { "user": { "enter": 0 } } }"#
+    )
+    .is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn profile_with_extends() {
+    let dir = "/tmp/nswrap.test-profiles";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(format!("{}/nswrap/profiles/shared", dir)).unwrap();
+    std::fs::write(
+        format!("{}/nswrap/profiles/shared/base.toml", dir),
+        r#"
+        namespaces = ["user", "uts"]
+        id_map_preset = "current"
+        hostname = "base"
+
+        [process]
+        program = "/bin/sh"
+        env = { FOO = "base", BAR = "base" }
+        "#,
+    )
+    .unwrap();
+    std::fs::write(
+        format!("{}/nswrap/profiles/test-child.toml", dir),
+        r#"
+        extends = "shared/base.toml"
+        hostname = "child"
+
+        [process]
+        args = ["-c", "test \"$(uname -n)\" = child && test $FOO = base && test $BAR = child && exit 16"]
+        env = { BAR = "child" }
+        "#,
+    )
+    .unwrap();
+    std::fs::write(
+        format!("{}/nswrap/profiles/test-loop.toml", dir),
+        "extends = \"test-loop\"",
+    )
+    .unwrap();
+    let dirs = [format!("{}/nswrap/profiles", dir)];
+
+    let mut wrap = Wrap::new();
+    profile::Profile::load_in("test-child", &dirs)
+        .unwrap()
+        .apply(&mut wrap)
+        .unwrap();
+    assert_eq!(Some(16), wrap.status().unwrap().code());
+    assert!(matches!(
+        profile::Profile::load_in("test-loop", &dirs),
+        Err(error::Error::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        profile::Profile::load_in("test-missing", &dirs),
+        Err(error::Error::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        profile::Profile::from_toml("namespace = [\"user\"]"),
+        Err(error::Error::InvalidConfig(_))
+    ));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn profile_root_replaced_as_a_whole() {
+    let dir = "/tmp/nswrap.test-profile-root";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    std::fs::write(
+        format!("{}/base.toml", dir),
+        "[overlay]\nlower = [\"/usr\"]\n",
+    )
+    .unwrap();
+    std::fs::write(
+        format!("{}/child.toml", dir),
+        "extends = \"./base.toml\"\n[root_dir]\npath = \"/srv\"\n",
+    )
+    .unwrap();
+    let profile = profile::Profile::from_file(format!("{}/child.toml", dir)).unwrap();
+    assert!(profile.overlay.is_none());
+    assert_eq!(
+        Some(std::path::Path::new("/srv")),
+        profile.root_dir.as_ref().map(|r| r.path().as_path())
+    );
+
+    let mut both = profile.clone();
+    both.sandbox_mnt = Some(Default::default());
+    assert!(matches!(
+        both.apply(&mut Wrap::new()),
+        Err(error::Error::InvalidConfig(_))
+    ));
+}
+
+#[test]
+fn bwrap_compatible_cli() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut args: Vec<String> = Vec::new();
+    for dir in ["usr", "bin", "lib", "lib64"] {
+        let host = format!("/{}", dir);
+        match std::fs::read_link(&host) {
+            Ok(target) => args.extend(["--symlink".into(), target.display().to_string(), host]),
+            Err(_) if std::path::Path::new(&host).exists() => {
+                args.extend(["--ro-bind".into(), host.clone(), host])
+            }
+            Err(_) => (),
+        }
+    }
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nswrap"))
+        .args(["--unshare-user", "--unshare-uts", "--hostname", "cli"])
+        .args(&args)
+        .args([
+            "--dev", "/dev", "--dir", "/work", "--chdir", "/work", "--setenv", "FOO", "bar",
+        ])
+        .args([
+            "--perms",
+            "0700",
+            "--tmpfs",
+            "/t",
+            "--file",
+            "0",
+            "/etc/motd",
+        ])
+        .args(["--unshare-pid", "--proc", "/proc"])
+        .args(["--bind", "/etc", "/b", "--dev-bind", "/etc", "/d"])
+        .args([
+            "--tmpfs",
+            "/r",
+            "--dir",
+            "/r/a",
+            "--tmpfs",
+            "/r/a",
+            "--remount-ro",
+            "/r",
+        ])
+        .args(["--tmpfs", "/s", "--remount-ro", "/s", "--tmpfs", "/s"])
+        .args(["--", "/bin/sh", "-c"])
+        .arg(
+            "test \"$(uname -n)\" = cli && test \"$FOO\" = bar && test \"$(pwd)\" = /work \
+             && test \"$(cat /etc/motd)\" = hello && test \"$(stat -c %a /t)\" = 700 \
+             && ! touch /usr/new 2>/dev/null \
+             && grep -q ' /b [^ ]*nodev' /proc/self/mountinfo \
+             && ! grep -q ' /d [^ ]*nodev' /proc/self/mountinfo \
+             && ! touch /r/x 2>/dev/null && touch /r/a/x /s/x && exit 16",
+        )
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"hello").unwrap();
+    assert_eq!(Some(16), child.wait().unwrap().code());
+
+    let out = Command::new(env!("CARGO_BIN_EXE_nswrap"))
+        .args(["--seccomp", "3", "/bin/true"])
+        .output()
+        .unwrap();
+    assert_eq!(Some(1), out.status.code());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--seccomp is not supported"));
+
+    let out = Command::new(env!("CARGO_BIN_EXE_nswrap"))
+        .args(&args)
+        .arg("/nonexist")
+        .output()
+        .unwrap();
+    assert_eq!(Some(1), out.status.code());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("execvp /nonexist"));
+
+    let status = Command::new(env!("CARGO_BIN_EXE_nswrap"))
+        .args(["--ro-bind", "/", "/", "--dev", "/dev", "--tmpfs", "/tmp"])
+        .args([
+            "/bin/sh",
+            "-c",
+            "test -c /dev/null && test -w /tmp && exit 3",
+        ])
+        .status()
+        .unwrap();
+    assert_eq!(Some(3), status.code());
+}
+
+#[test]
+fn bwrap_args_and_description() {
+    let mut wrap = Wrap::new_program("/bin/sh");
+    wrap.args([
+        "-c",
+        "test \"$(uname -n)\" = described && test \"$FOO\" = bar && exit 16",
+    ])
+    .unshare(config::NamespaceType::User)
+    .unshare(config::NamespaceType::Mount)
+    .unshare(config::NamespaceType::Uts)
+    .id_map_preset(config::IdMapPreset::Current)
+    .sandbox_mnt(true)
+    .hostname("described")
+    .dir("/work", 0o755)
+    .current_dir("/work")
+    .env("FOO", "bar");
+    for dir in ["usr", "bin", "lib", "lib64"] {
+        let host = format!("/{}", dir);
+        match std::fs::read_link(&host) {
+            Ok(target) => {
+                wrap.symlink(target, &host);
+            }
+            Err(_) if std::path::Path::new(&host).exists() => {
+                wrap.ro_bind(&host, &host);
+            }
+            Err(_) => (),
+        }
+    }
+
+    let args = wrap.to_bwrap_args();
+    assert!(!args.iter().any(|a| a == BWRAP_UNSUPPORTED));
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_nswrap"))
+        .args(&args)
+        .status()
+        .unwrap();
+    assert_eq!(Some(16), status.code());
+
+    let steps = wrap.describe();
+    let hostname = steps.find("set hostname described").unwrap();
+    let dir = steps.find("create dir /work").unwrap();
+    let exec = steps.find("exec \"/bin/sh\"").unwrap();
+    assert!(steps.starts_with("1. unshare namespaces: user, mount, uts\n"));
+    assert!(hostname < dir && dir < exec);
+    assert!(steps.contains("\"FOO\"=\"bar\", in /work"));
+
+    wrap.callback(|| 0).mask_path("/proc/kcore");
+    let args = wrap.to_bwrap_args();
+    let marked: Vec<_> = args
+        .windows(2)
+        .filter(|w| w[0] == BWRAP_UNSUPPORTED)
+        .map(|w| w[1].clone())
+        .collect();
+    assert_eq!(marked, ["mask /proc/kcore", "1 callbacks"]);
+    assert!(wrap.describe().contains("1 callbacks (opaque)"));
+
+    // Without a program, there is no command after `--`.
+    let mut wrap = Wrap::new();
+    wrap.callback(|| 0);
+    let args = wrap.to_bwrap_args();
+    assert_eq!(args.last().unwrap(), "1 callbacks");
+    assert!(!args.iter().any(|a| a == "--" || a.is_empty()));
+    assert_eq!(Wrap::new().to_bwrap_args().last().unwrap(), "no program");
+}
+
+#[test]
+fn validate_before_spawn() {
+    use validate::Severity;
+
+    let mut wrap = Wrap::new_program("/bin/true");
+    wrap.unshare(config::NamespaceType::User)
+        .nsenter(config::NamespaceType::User, 0)
+        .sandbox_mnt(true)
+        .uid_map(1000, 0, 10)
+        .uid_map(1005, 100, 1)
+        .hostname("none")
+        .bind("/usr", "usr")
+        .proc_mount(config::ProcOptions::default());
+    let errors: Vec<_> = wrap
+        .validate()
+        .into_iter()
+        .filter(|d| d.severity == Severity::Error)
+        .map(|d| d.message)
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            "user namespace is both entered and unshared",
+            "uid maps 1000->0 (10) and 1005->100 (1) overlap",
+            "a mount namespace is needed for sandbox_mnt",
+            "a mount namespace is needed for mounts, files, dirs and symlinks",
+            "a mount namespace is needed for proc_mount",
+            "mount destination usr is not an absolute path",
+            "hostname needs a uts namespace",
+        ]
+    );
+    assert!(matches!(wrap.spawn(), Err(error::Error::InvalidConfig(_))));
+
+    let mut wrap = Wrap::new_program("/bin/true");
+    wrap.unshare(config::NamespaceType::Time);
+    assert!(
+        wrap.validate()
+            .iter()
+            .any(|d| d.severity == Severity::Error
+                && d.message == "time namespaces are not supported")
+    );
+    assert!(matches!(wrap.spawn(), Err(error::Error::InvalidConfig(_))));
+
+    let mut wrap = Wrap::new();
+    wrap.unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .proc_mount(config::ProcOptions::default());
+    let diagnostics = wrap.validate();
+    assert!(diagnostics.iter().all(|d| d.severity == Severity::Warning));
+    assert_eq!(3, diagnostics.len());
+
+    let mut wrap = Wrap::new_program("/bin/true");
+    wrap.unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .file("/motd", "hello", 0o644);
+    let severities = |wrap: &Wrap| -> Vec<_> {
+        wrap.validate()
+            .into_iter()
+            .filter(|d| d.message.starts_with("files"))
+            .map(|d| d.severity)
+            .collect()
+    };
+    assert_eq!(severities(&wrap), [Severity::Error]);
+    wrap.root_dir("/tmp", false);
+    assert_eq!(severities(&wrap), [Severity::Warning]);
+    wrap.sandbox_mnt(true);
+    assert_eq!(severities(&wrap), [Severity::Warning]);
+
+    // Ids of an idmapped mount are translated with the maps of the child.
+    let map = config::IdMapBuilder::default()
+        .host_id(1234)
+        .container_id(5)
+        .size(10)
+        .build()
+        .unwrap();
+    let mnt = config::MountBuilder::default()
+        .destination("/data".into())
+        .typ(None)
+        .source(Some("/tmp".into()))
+        .options(Some(vec!["rbind".into()]))
+        .uid_mappings(vec![map])
+        .build()
+        .unwrap();
+    let mut wrap = Wrap::new_program("/bin/true");
+    wrap.unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Mount)
+        .sandbox_mnt(true)
+        .uid_map(1000, 0, 10)
+        .mount(mnt);
+    let errors: Vec<_> = wrap
+        .validate()
+        .into_iter()
+        .filter(|d| d.severity == Severity::Error)
+        .map(|d| d.message)
+        .collect();
+    assert_eq!(
+        errors,
+        ["uid mapping of the mount on /data uses unmapped ids 5..15"]
+    );
+}
+
+#[test]
+fn kernel_feature_probe() {
+    let userns = std::fs::read_link("/proc/self/ns/user").unwrap();
+    let features = util::probe::probe();
+    assert_eq!(userns, std::fs::read_link("/proc/self/ns/user").unwrap());
+
+    // Other tests need these.
+    assert!(features.user_namespaces.allowed);
+    assert!(features.mount_api);
+    for ns in ["mount", "user", "pid", "network"] {
+        assert!(features.namespaces.contains(&ns));
+    }
+    assert_eq!(
+        features.seccomp.user_notif,
+        features.seccomp.actions.contains(&"SCMP_ACT_USER_NOTIF")
+    );
+
+    #[cfg(feature = "serde")]
+    {
+        let oci = oci::features();
+        let linux = oci.linux.unwrap();
+        assert!(!linux.namespaces.contains(&"time".to_string()));
+        assert_eq!(
+            Some(features.idmapped_mounts),
+            linux
+                .mount_extensions
+                .and_then(|m| m.idmap)
+                .map(|i| i.enabled)
+        );
+        assert!(oci.mount_options.contains(&"rbind".to_string()));
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn typed_run_result() {
+    let mut wrap = Wrap::new();
+    wrap.unshare(config::NamespaceType::User)
+        .unshare(config::NamespaceType::Uts)
+        .id_map_preset(config::IdMapPreset::Root)
+        .hostname("typed");
+    let (uid, hostname, big): (u32, String, Vec<u64>) = wrap
+        .run(|| {
+            let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap();
+            (util::get_uid(), hostname, (0..100_000).collect())
+        })
+        .unwrap();
+    assert_eq!(0, uid);
+    assert_eq!("typed\n", hostname);
+    assert_eq!(Some(&99_999), big.last());
+
+    let mut wrap = Wrap::new();
+    wrap.unshare(config::NamespaceType::User);
+    let res: Result<u32, _> = wrap.run(|| unsafe { libc::_exit(3) });
+    assert!(matches!(res, Err(error::Error::NoResult(e)) if e.ends_with("exited with 3")));
+
+    // JSON objects only have string keys.
+    let mut wrap = Wrap::new();
+    wrap.unshare(config::NamespaceType::User);
+    let res: Result<std::collections::HashMap<(u8, u8), u8>, _> = wrap.run(|| [((1, 2), 3)].into());
+    assert!(matches!(res, Err(error::Error::NoResult(e)) if e.starts_with("cannot serialize")));
+}